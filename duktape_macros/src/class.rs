@@ -0,0 +1,110 @@
+use proc_macro2::TokenStream;
+use syn::{DeriveInput, Error, Ident, Lit, LitStr, Meta, NestedMeta};
+
+/// The options given in `#[js_class(...)]`.
+struct ClassOptions {
+    name: Option<String>,
+    constructor: Option<Ident>,
+    /// Each method's JavaScript name and Rust name.
+    methods: Vec<(String, Ident)>
+}
+
+/// Generate an implementation of `JsClass` for `input`.
+pub fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let ty = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics, "#[derive(JsClass)] can't be used on generic types"));
+    }
+    let opts = try!(parse_options(&input));
+    let name = opts.name.unwrap_or_else(|| ty.to_string());
+    let constructor = try!(opts.constructor.ok_or_else(|| Error::new_spanned(
+        ty, "#[derive(JsClass)] requires #[js_class(constructor = \"...\")]")));
+    let js_names = opts.methods.iter().map(|m| &m.0);
+    let rust_names = opts.methods.iter().map(|m| &m.1);
+
+    Ok(quote! {
+        impl ::duktape::JsClass for #ty {
+            fn class_name() -> &'static str { #name }
+
+            fn construct(ctx: &mut ::duktape::Context,
+                         args: &[::duktape::Value<'static>]) ->
+                ::duktape::DuktapeResult<#ty>
+            {
+                #ty::#constructor(ctx, args)
+            }
+
+            fn methods() -> ::std::vec::Vec<(&'static str,
+                                             ::duktape::Method<#ty>,
+                                             ::std::option::Option<u16>)>
+            {
+                vec!(#((#js_names,
+                        #ty::#rust_names as ::duktape::Method<#ty>,
+                        None)),*)
+            }
+        }
+    })
+}
+
+/// Collect the options from every `#[js_class(...)]` attribute.
+fn parse_options(input: &DeriveInput) -> syn::Result<ClassOptions> {
+    let mut opts = ClassOptions{name: None, constructor: None, methods: vec!()};
+    for attr in input.attrs.iter().filter(|a| a.path.is_ident("js_class")) {
+        let list = match try!(attr.parse_meta()) {
+            Meta::List(list) => list,
+            other => {
+                return Err(Error::new_spanned(other,
+                                              "expected #[js_class(...)]"));
+            }
+        };
+        for nested in list.nested.iter() {
+            match *nested {
+                NestedMeta::Meta(Meta::NameValue(ref nv))
+                    if nv.path.is_ident("name") =>
+                {
+                    opts.name = Some(try!(lit_str(&nv.lit)).value());
+                }
+                NestedMeta::Meta(Meta::NameValue(ref nv))
+                    if nv.path.is_ident("constructor") =>
+                {
+                    opts.constructor = Some(try!(try!(lit_str(&nv.lit)).parse()));
+                }
+                NestedMeta::Meta(Meta::List(ref methods))
+                    if methods.path.is_ident("methods") =>
+                {
+                    for method in methods.nested.iter() {
+                        opts.methods.push(try!(parse_method(method)));
+                    }
+                }
+                ref other => {
+                    return Err(Error::new_spanned(other,
+                                                  "unknown js_class option"));
+                }
+            }
+        }
+    }
+    Ok(opts)
+}
+
+/// Parse either `name` or `name = "jsName"`.
+fn parse_method(method: &NestedMeta) -> syn::Result<(String, Ident)> {
+    let (path, js_name) = match *method {
+        NestedMeta::Meta(Meta::Path(ref path)) => (path, None),
+        NestedMeta::Meta(Meta::NameValue(ref nv)) =>
+            (&nv.path, Some(try!(lit_str(&nv.lit)).value())),
+        ref other => {
+            return Err(Error::new_spanned(other, "expected a method name"));
+        }
+    };
+    let ident = try!(path.get_ident().cloned().ok_or_else(|| {
+        Error::new_spanned(path, "expected a method name")
+    }));
+    Ok((js_name.unwrap_or_else(|| ident.to_string()), ident))
+}
+
+fn lit_str(lit: &Lit) -> syn::Result<&LitStr> {
+    match *lit {
+        Lit::Str(ref s) => Ok(s),
+        ref other => Err(Error::new_spanned(other, "expected a string"))
+    }
+}
@@ -0,0 +1,95 @@
+use proc_macro2::{Span, TokenStream};
+use syn::{Error, FnArg, Ident, ItemFn, ReturnType, Type, Visibility};
+
+/// Generate a callback wrapping `item`, which is moved inside it.
+pub fn expand(item: ItemFn) -> syn::Result<TokenStream> {
+    let name = item.sig.ident.clone();
+    let name_str = name.to_string();
+    if !item.sig.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &item.sig.generics,
+            "#[js_function] can't be used on generic functions"));
+    }
+    if let ReturnType::Default = item.sig.output {
+        return Err(Error::new_spanned(
+            &item.sig, "#[js_function] functions must return a DuktapeResult"));
+    }
+
+    // Work out which arguments come from JavaScript.
+    let mut takes_ctx = false;
+    let mut types = vec!();
+    for (i, arg) in item.sig.inputs.iter().enumerate() {
+        match *arg {
+            FnArg::Receiver(ref recv) => {
+                return Err(Error::new_spanned(
+                    recv, "#[js_function] can't be used on methods"));
+            }
+            FnArg::Typed(ref pat) => {
+                if i == 0 && is_context_ref(&pat.ty) {
+                    takes_ctx = true;
+                } else {
+                    types.push(pat.ty.clone());
+                }
+            }
+        }
+    }
+    let count = types.len();
+    let vars: Vec<Ident> = (0..count)
+        .map(|i| Ident::new(&format!("arg{}", i), Span::call_site()))
+        .collect();
+    let idxs: Vec<usize> = (0..count).collect();
+    let ctx_arg = if takes_ctx { quote!(ctx,) } else { quote!() };
+
+    // Documentation belongs to the callback, and everything else stays on
+    // the original function.
+    let (docs, attrs): (Vec<_>, Vec<_>) =
+        item.attrs.iter().cloned().partition(|a| a.path.is_ident("doc"));
+    let vis = item.vis.clone();
+    let mut inner = item;
+    inner.attrs = attrs;
+    inner.vis = Visibility::Inherited;
+    inner.sig.ident = Ident::new("inner", Span::call_site());
+
+    Ok(quote! {
+        #(#docs)*
+        #vis fn #name(ctx: &mut ::duktape::Context,
+                      args: &[::duktape::Value<'static>]) ->
+            ::duktape::DuktapeResult<::duktape::Value<'static>>
+        {
+            #inner
+
+            if let Err(err) = ::duktape::macro_support::check_arity(
+                #name_str, #count, args.len())
+            {
+                return Err(err);
+            }
+            #(
+                let #vars: #types = match ::duktape::macro_support::decode_arg(
+                    ctx, #name_str, #idxs)
+                {
+                    Ok(value) => value,
+                    Err(err) => return Err(err)
+                };
+            )*
+            match inner(#ctx_arg #(#vars),*) {
+                Ok(result) =>
+                    ::duktape::macro_support::encode_result(ctx, &result),
+                Err(err) => Err(err)
+            }
+        }
+    })
+}
+
+/// Is `ty` a `&mut Context`?
+fn is_context_ref(ty: &Type) -> bool {
+    match *ty {
+        Type::Reference(ref r) if r.mutability.is_some() => {
+            match *r.elem {
+                Type::Path(ref p) => p.path.segments.last()
+                    .map(|seg| seg.ident == "Context").unwrap_or(false),
+                _ => false
+            }
+        }
+        _ => false
+    }
+}
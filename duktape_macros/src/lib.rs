@@ -0,0 +1,55 @@
+//! Procedural macros for the [duktape][] crate.  These are re-exported by
+//! `duktape` itself, so you shouldn't need to depend on this crate
+//! directly.
+//!
+//! [duktape]: https://github.com/emk/duktape-rs
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use] extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use syn::{DeriveInput, ItemFn};
+
+mod function;
+mod class;
+
+/// Turn an ordinary Rust function into a `duktape::Callback`.  Each
+/// argument must be `DuktapeDecodable`, and the function must return a
+/// `DuktapeResult` of something `DuktapeEncodable`.  If the first argument
+/// is a `&mut Context`, it is passed the context the function was called
+/// from.
+///
+/// The generated callback checks that it was passed the right number of
+/// arguments and decodes them, reporting any problems to JavaScript as a
+/// `TypeError`.  It can then be registered with `Context::register`.  Pass
+/// `None` as the argument count: with `Some(n)`, duktape pads or drops
+/// arguments to make `n` before the callback sees them, so the count is
+/// never wrong.
+#[proc_macro_attribute]
+pub fn js_function(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let attr = proc_macro2::TokenStream::from(attr);
+        return syn::Error::new_spanned(attr, "#[js_function] takes no options")
+            .to_compile_error().into();
+    }
+    let item = syn::parse_macro_input!(item as ItemFn);
+    function::expand(item).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+/// Implement `duktape::JsClass` using the options in a `#[js_class(...)]`
+/// attribute:
+///
+/// - `constructor = "new"` names an associated function with the signature
+///   of `JsClass::construct`.  This is required.
+/// - `methods(inc, call_back = "callBack")` lists the methods to put on the
+///   prototype, each of which must be a `duktape::Method`.  A method may
+///   be given a different JavaScript name.
+/// - `name = "Counter"` sets the class name, which defaults to the name of
+///   the type.
+#[proc_macro_derive(JsClass, attributes(js_class))]
+pub fn derive_js_class(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    class::expand(input).unwrap_or_else(|err| err.to_compile_error()).into()
+}
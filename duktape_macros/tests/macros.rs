@@ -0,0 +1,107 @@
+#[macro_use] extern crate duktape;
+
+use duktape::{Context, DuktapeResult, ErrorCode, JsClass, Value, js_function};
+
+#[js_function]
+fn add(x: f64, y: f64) -> DuktapeResult<f64> {
+    Ok(x + y)
+}
+
+#[js_function]
+fn repeat(ctx: &mut Context, code: String, times: u32) ->
+    DuktapeResult<Vec<f64>>
+{
+    (0..times).map(|_| ctx.eval_as::<f64>(&code)).collect()
+}
+
+#[js_function]
+fn answer() -> DuktapeResult<u32> { Ok(42) }
+
+#[test]
+fn test_js_function() {
+    let mut ctx = Context::new().unwrap();
+    ctx.register("add", add, None);
+    ctx.register("repeat", repeat, None);
+    ctx.register("answer", answer, None);
+
+    assert_eq!(Value::Number(5.0), ctx.eval("add(2, 3)").unwrap());
+    assert_eq!(vec!(1.0, 2.0, 3.0),
+               ctx.eval_as::<Vec<f64>>("var n = 0; repeat('++n', 3)")
+                   .unwrap());
+    assert_eq!(42, ctx.eval_as::<u32>("answer()").unwrap());
+
+    let err = ctx.eval("answer(1)").unwrap_err();
+    assert_eq!(ErrorCode::Type, err.code());
+    assert_eq!(Some("answer expects 0 arguments, got 1"), err.message());
+    let err = ctx.eval("add(1)").unwrap_err();
+    assert_eq!(Some("add expects 2 arguments, got 1"), err.message());
+    let err = ctx.eval("repeat('1', 'x')").unwrap_err();
+    assert_eq!(Some("repeat: argument 1: Expected number"), err.message());
+}
+
+#[test]
+fn test_js_call() {
+    let mut ctx = Context::new().unwrap();
+    ctx.eval("function join(a, b) { return a + '-' + b; } \
+              function none() { return 'none'; }").unwrap();
+    assert_eq!(Ok(Value::String("x-1".into())),
+               js_call!(ctx, "join", "x", 1.0f64));
+    assert_eq!(Ok(Value::String("none".into())), js_call!(ctx, "none"));
+    assert!(js_call!(ctx, "missing", true).is_err());
+}
+
+#[derive(JsClass)]
+#[js_class(constructor = "new", methods(inc, current = "currentValue"))]
+struct Counter { count: f64 }
+
+impl Counter {
+    fn new(_ctx: &mut Context, args: &[Value<'static>]) ->
+        DuktapeResult<Counter>
+    {
+        match args.get(0) {
+            Some(&Value::Number(n)) => Ok(Counter{count: n}),
+            _ => Ok(Counter{count: 0.0})
+        }
+    }
+
+    fn inc(&mut self, _ctx: &mut Context, _args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        self.count += 1.0;
+        Ok(Value::Number(self.count))
+    }
+
+    fn current(&mut self, _ctx: &mut Context, _args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        Ok(Value::Number(self.count))
+    }
+}
+
+#[derive(JsClass)]
+#[js_class(name = "Empty", constructor = "create")]
+struct Nothing;
+
+impl Nothing {
+    fn create(_ctx: &mut Context, _args: &[Value<'static>]) ->
+        DuktapeResult<Nothing>
+    {
+        Ok(Nothing)
+    }
+}
+
+#[test]
+fn test_derive_js_class() {
+    assert_eq!("Counter", Counter::class_name());
+    assert_eq!(2, Counter::methods().len());
+    assert_eq!("Empty", Nothing::class_name());
+
+    let mut ctx = Context::new().unwrap();
+    ctx.register_class::<Counter>();
+    ctx.register_class::<Nothing>();
+    assert_eq!(Value::Number(6.0),
+               ctx.eval("var c = new Counter(5); c.inc(); c.currentValue()")
+                   .unwrap());
+    assert_eq!(Value::Bool(true),
+               ctx.eval("new Empty() instanceof Empty").unwrap());
+}
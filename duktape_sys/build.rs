@@ -10,9 +10,18 @@ fn main() {
     cflags.push_str(" -std=c99");
     set_var("CFLAGS", cflags);
 
+    // Enable the interrupt counter, and have duktape ask glue.c whether a
+    // script has run for too long.  This is what allows runaway scripts to
+    // be interrupted with a RangeError.
+    // internals.c includes duktape.c, so that it can count heap objects.
     &gcc::Config::new()
-        .file(Path::new("duktape/src/duktape.c"))
+        .file(Path::new("src/internals.c"))
         .file(Path::new("src/glue.c"))
         .include("duktape/src")
+        .define("DUK_OPT_INTERRUPT_COUNTER", None)
+        .define("DUK_OPT_EXEC_TIMEOUT_CHECK(udata)",
+                Some("duk_rust_exec_timeout_check(udata)"))
+        .define("DUK_OPT_DECLARE",
+                Some("extern duk_bool_t duk_rust_exec_timeout_check(void *udata);"))
         .compile("libduktape.a");
 }
@@ -218,6 +218,8 @@ extern "C" {
      -> duk_bool_t;
     pub fn duk_is_primitive(ctx: *mut duk_context, index: duk_idx_t)
      -> duk_bool_t;
+    pub fn duk_get_error_code(ctx: *mut duk_context, index: duk_idx_t)
+     -> duk_errcode_t;
     pub fn duk_get_boolean(ctx: *mut duk_context, index: duk_idx_t)
      -> duk_bool_t;
     pub fn duk_get_number(ctx: *mut duk_context, index: duk_idx_t)
@@ -237,6 +239,8 @@ extern "C" {
      -> duk_c_function;
     pub fn duk_get_context(ctx: *mut duk_context, index: duk_idx_t)
      -> *mut duk_context;
+    pub fn duk_get_heapptr(ctx: *mut duk_context, index: duk_idx_t)
+     -> *mut ::libc::c_void;
     pub fn duk_get_length(ctx: *mut duk_context, index: duk_idx_t)
      -> duk_size_t;
     pub fn duk_require_undefined(ctx: *mut duk_context, index: duk_idx_t);
@@ -399,6 +403,8 @@ extern "C" {
                            src_buffer: *const ::libc::c_char,
                            src_length: duk_size_t, flags: duk_uint_t)
      -> duk_int_t;
+    pub fn duk_dump_function(ctx: *mut duk_context);
+    pub fn duk_load_function(ctx: *mut duk_context);
     pub fn duk_log(ctx: *mut duk_context, level: duk_int_t,
                    fmt: *const ::libc::c_char, ...);
     pub fn duk_push_context_dump(ctx: *mut duk_context);
@@ -21,7 +21,7 @@
 int main(int argc, char **argv) {
     // File header.
     printf("//! Platform-specific values generated by defgen.c.\n\n");
-    printf("use libc::types::os::arch::c95::{c_long, c_double};\n\n");
+    printf("use libc::{c_long, c_double};\n\n");
 
     // Print type declarations.
     INT_TYPE(duk_errcode_t);
@@ -1,6 +1,6 @@
 //! Platform-specific values generated by defgen.c.
 
-use libc::types::os::arch::c95::{c_long, c_double};
+use libc::{c_long, c_double};
 
 pub type duk_errcode_t = i32;
 pub type duk_idx_t = i32;
@@ -17,7 +17,7 @@ pub type duk_int32_t = i32;
 pub type duk_uint32_t = u32;
 pub type duk_uint16_t = u16;
 pub type duk_double_t = c_double;
-pub const DUK_VERSION: c_long = 10002;
+pub const DUK_VERSION: c_long = 10300;
 pub const DUK_INVALID_INDEX: duk_idx_t = -2147483648;
 pub const DUK_VARARGS: duk_int_t = -1;
 pub const DUK_API_ENTRY_STACK: duk_idx_t = 64;
@@ -1,5 +1,22 @@
 #include "duktape.h"
 
+/// We need duk_dump_function and duk_load_function, which first appeared in
+/// Duktape 1.3.0.
+#if DUK_VERSION < 10300
+#error "duktape_sys needs Duktape 1.3.0 or later in duktape_sys/duktape"
+#endif
+
+/// The internal property which holds the Rust implementation of a
+/// function created with `duk_push_rust_function`.
+#define DUK_RUST_IMPL_PROP "\xff" "rimpl"
+
+/// Returned by Rust functions which want us to throw the value on the top
+/// of the stack.  This must match the value in glue.rs.
+#define DUK_RET_RUST_THROW (-1000)
+
+/// The type of the Rust function which checks for execution timeouts.
+typedef duk_bool_t (*duk_rust_exec_timeout_check_function)(void *udata);
+
 /// A custom add-on to the duktape API, replacing the macro
 /// `duk_push_error_object`,
 extern duk_idx_t
@@ -10,3 +27,239 @@ duk_push_error_object_string(duk_context *ctx, duk_errcode_t err_code,
     return duk_push_error_object_raw(ctx, err_code, filename, line, "%s",
                                      message);
 }
+
+/// Is the value at `idx` an `Error`, or an object which inherits from one?
+/// This is a macro in duktape.h.
+extern duk_bool_t
+duk_rust_is_error(duk_context *ctx, duk_idx_t idx)
+{
+    return duk_is_error(ctx, idx);
+}
+
+/// Calls the Rust implementation of the current function.  Throwing an
+/// error performs a longjmp, which must never cross a Rust stack frame, so
+/// Rust code returns DUK_RET_RUST_THROW instead, and we do the actual
+/// throwing here once the Rust code has returned.
+extern duk_ret_t
+duk_rust_trampoline(duk_context *ctx)
+{
+    duk_c_function impl;
+    duk_ret_t ret;
+
+    duk_push_current_function(ctx);
+    duk_get_prop_string(ctx, -1, DUK_RUST_IMPL_PROP);
+    impl = (duk_c_function) duk_get_pointer(ctx, -1);
+    duk_pop_2(ctx);
+
+    ret = impl(ctx);
+    if (ret == DUK_RET_RUST_THROW) {
+        duk_throw(ctx);
+    }
+    return ret;
+}
+
+/// Like `duk_push_c_function`, but `impl` is called via
+/// `duk_rust_trampoline`, so it may return DUK_RET_RUST_THROW.
+extern duk_idx_t
+duk_push_rust_function(duk_context *ctx, duk_c_function impl,
+                       duk_idx_t nargs)
+{
+    duk_idx_t idx = duk_push_c_function(ctx, duk_rust_trampoline, nargs);
+    duk_push_pointer(ctx, (void *) impl);
+    duk_put_prop_string(ctx, idx, DUK_RUST_IMPL_PROP);
+    return idx;
+}
+
+/// The Rust function which implements every function created with
+/// `duk_rust_dispatch_trampoline`, or NULL if none has been set.
+static duk_c_function duk_rust_dispatch_fn = NULL;
+
+/// Set the function called by `duk_rust_dispatch_trampoline`.
+extern void
+duk_rust_set_dispatch(duk_c_function dispatch)
+{
+    duk_rust_dispatch_fn = dispatch;
+}
+
+/// Like `duk_rust_trampoline`, but always calls the function set with
+/// `duk_rust_set_dispatch`, which uses the magic number to tell functions
+/// apart.  Nothing is stored on the function object, so scripts have
+/// nothing to tamper with.
+extern duk_ret_t
+duk_rust_dispatch_trampoline(duk_context *ctx)
+{
+    duk_ret_t ret;
+
+    if (duk_rust_dispatch_fn == NULL) {
+        return DUK_RET_TYPE_ERROR;
+    }
+    ret = duk_rust_dispatch_fn(ctx);
+    if (ret == DUK_RET_RUST_THROW) {
+        duk_throw(ctx);
+    }
+    return ret;
+}
+
+/// Used by `duk_rust_pnew` to call `duk_new` inside `duk_safe_call`.  The
+/// argument count is passed on top of the stack.
+static duk_ret_t
+duk_rust_new_helper(duk_context *ctx)
+{
+    duk_idx_t nargs = (duk_idx_t) duk_require_int(ctx, -1);
+    duk_pop(ctx);
+    duk_new(ctx, nargs);
+    return 1;
+}
+
+/// A protected version of `duk_new`, which leaves either the new object or
+/// an error on the stack in place of the constructor and its arguments,
+/// and returns DUK_EXEC_SUCCESS or DUK_EXEC_ERROR.
+extern duk_int_t
+duk_rust_pnew(duk_context *ctx, duk_idx_t nargs)
+{
+    duk_push_int(ctx, nargs);
+    return duk_safe_call(ctx, duk_rust_new_helper, nargs + 2, 1);
+}
+
+/// Used by `duk_rust_pdump_function`.
+static duk_ret_t
+duk_rust_dump_helper(duk_context *ctx)
+{
+    duk_dump_function(ctx);
+    return 1;
+}
+
+/// A protected version of `duk_dump_function`, which replaces the function
+/// on top of the stack with either a bytecode buffer or an error, and
+/// returns DUK_EXEC_SUCCESS or DUK_EXEC_ERROR.
+extern duk_int_t
+duk_rust_pdump_function(duk_context *ctx)
+{
+    return duk_safe_call(ctx, duk_rust_dump_helper, 1, 1);
+}
+
+/// Used by `duk_rust_pload_function`.
+static duk_ret_t
+duk_rust_load_helper(duk_context *ctx)
+{
+    duk_load_function(ctx);
+    return 1;
+}
+
+/// A protected version of `duk_load_function`, which replaces the bytecode
+/// buffer on top of the stack with either a function or an error.  Duktape
+/// does not validate bytecode, so this only catches some kinds of damage.
+extern duk_int_t
+duk_rust_pload_function(duk_context *ctx)
+{
+    return duk_safe_call(ctx, duk_rust_load_helper, 1, 1);
+}
+
+/// Operations performed by `duk_rust_pprop`.  These must match the values
+/// in glue.rs.
+#define DUK_RUST_PROP_GET  0
+#define DUK_RUST_PROP_PUT  1
+#define DUK_RUST_PROP_HAS  2
+#define DUK_RUST_PROP_DEL  3
+#define DUK_RUST_PROP_ENUM 4
+
+/// The global stash key for the function pushed by `duk_rust_push_deleter`.
+#define DUK_RUST_DELETER_KEY "rust-deleter"
+
+/// Push a non-strict JavaScript function which deletes a property and
+/// returns the result, compiling it the first time we need it.
+static void
+duk_rust_push_deleter(duk_context *ctx)
+{
+    duk_push_global_stash(ctx);
+    if (!duk_get_prop_string(ctx, -1, DUK_RUST_DELETER_KEY)) {
+        duk_pop(ctx);
+        duk_push_string(ctx, "function (obj, key) { return delete obj[key]; }");
+        duk_push_string(ctx, "delete");
+        duk_compile(ctx, DUK_COMPILE_FUNCTION);
+        duk_dup_top(ctx);
+        duk_put_prop_string(ctx, -3, DUK_RUST_DELETER_KEY);
+    }
+    duk_remove(ctx, -2);
+}
+
+/// Used by `duk_rust_pprop`.  The operation is passed on top of the stack.
+static duk_ret_t
+duk_rust_prop_helper(duk_context *ctx)
+{
+    duk_int_t op = duk_require_int(ctx, -1);
+    duk_pop(ctx);
+    switch (op) {
+    case DUK_RUST_PROP_GET:
+        duk_get_prop(ctx, -2);
+        return 1;
+    case DUK_RUST_PROP_PUT:
+        duk_put_prop(ctx, -3);
+        return 0;
+    case DUK_RUST_PROP_HAS:
+        duk_push_boolean(ctx, duk_has_prop(ctx, -2));
+        return 1;
+    case DUK_RUST_PROP_DEL:
+        /* duk_del_prop always acts like a strict mode delete, and throws
+         * if the property isn't configurable.  A non-strict delete returns
+         * false instead, but still lets proxy traps throw. */
+        duk_rust_push_deleter(ctx);
+        duk_insert(ctx, -3);
+        duk_call(ctx, 2);
+        return 1;
+    case DUK_RUST_PROP_ENUM:
+        duk_enum(ctx, -2, duk_require_uint(ctx, -1));
+        return 1;
+    default:
+        return DUK_RET_API_ERROR;
+    }
+}
+
+/// Protected property access, since getters, setters and proxies may throw.
+/// The `nargs` values on top of the stack are replaced by a single result
+/// or an error, and we return DUK_EXEC_SUCCESS or DUK_EXEC_ERROR.  The
+/// operations are:
+///
+///   GET:  [ obj key ]       -> [ value ]
+///   PUT:  [ obj key value ] -> [ undefined ]
+///   HAS:  [ obj key ]       -> [ boolean ]
+///   DEL:  [ obj key ]       -> [ boolean ]
+///   ENUM: [ obj flags ]     -> [ enumerator ]
+extern duk_int_t
+duk_rust_pprop(duk_context *ctx, duk_int_t op, duk_idx_t nargs)
+{
+    duk_push_int(ctx, op);
+    return duk_safe_call(ctx, duk_rust_prop_helper, nargs + 1, 1);
+}
+
+/// The version of the duktape we were compiled and linked with, which may
+/// differ from the `DUK_VERSION` recorded in generated.rs.
+extern duk_int_t
+duk_rust_get_version(void)
+{
+    return (duk_int_t) DUK_VERSION;
+}
+
+/// The Rust function which decides whether a script has run for too long,
+/// or NULL if none has been set.
+static duk_rust_exec_timeout_check_function duk_rust_exec_timeout_check_fn =
+    NULL;
+
+/// Set the function called by `duk_rust_exec_timeout_check`.
+extern void
+duk_rust_set_exec_timeout_check(duk_rust_exec_timeout_check_function check)
+{
+    duk_rust_exec_timeout_check_fn = check;
+}
+
+/// Called periodically by the duktape interpreter (see
+/// DUK_OPT_EXEC_TIMEOUT_CHECK in build.rs) with the heap's allocator
+/// udata.  Returning true makes the running script throw a RangeError.
+extern duk_bool_t
+duk_rust_exec_timeout_check(void *udata)
+{
+    if (duk_rust_exec_timeout_check_fn == NULL) {
+        return 0;
+    }
+    return duk_rust_exec_timeout_check_fn(udata);
+}
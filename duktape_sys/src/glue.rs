@@ -4,6 +4,24 @@
 use generated::*;
 use bindings::*;
 
+/// Return this from a function pushed with `duk_push_rust_function` to
+/// throw the value on the top of the stack.  Rust code must never call
+/// `duk_throw` itself, because that would longjmp across Rust stack frames.
+pub const DUK_RET_RUST_THROW: duk_ret_t = -1000;
+
+/// Operations for `duk_rust_pprop`.  These must match the values in glue.c.
+pub const DUK_RUST_PROP_GET: duk_int_t = 0;
+pub const DUK_RUST_PROP_PUT: duk_int_t = 1;
+pub const DUK_RUST_PROP_HAS: duk_int_t = 2;
+pub const DUK_RUST_PROP_DEL: duk_int_t = 3;
+pub const DUK_RUST_PROP_ENUM: duk_int_t = 4;
+
+/// Decides whether the script running on the heap with allocator `udata`
+/// should be interrupted.
+pub type duk_rust_exec_timeout_check_function =
+    ::std::option::Option<unsafe extern "C" fn(udata: *mut ::libc::c_void)
+                                               -> duk_bool_t>;
+
 extern "C" {
     /// A wrapper around duk_push_error_object, which relies on varargs in
     /// the original API.
@@ -11,4 +29,61 @@ extern "C" {
         ctx: *mut duk_context, err_code: duk_errcode_t,
         filename: *const i8, line: duk_int_t,
         message: *const i8) -> duk_idx_t;
+
+    /// Is the value at `idx` an `Error`, or an object which inherits from
+    /// one?  This replaces the `duk_is_error` macro.
+    pub fn duk_rust_is_error(ctx: *mut duk_context, idx: duk_idx_t) ->
+        duk_bool_t;
+
+    /// The C function used by `duk_push_rust_function`, which calls the
+    /// Rust implementation and throws if it returns `DUK_RET_RUST_THROW`.
+    pub fn duk_rust_trampoline(ctx: *mut duk_context) -> duk_ret_t;
+
+    /// Like `duk_rust_trampoline`, but calls the single function set with
+    /// `duk_rust_set_dispatch`, which can tell functions apart using their
+    /// magic numbers.
+    pub fn duk_rust_dispatch_trampoline(ctx: *mut duk_context) -> duk_ret_t;
+
+    /// Set the function called by `duk_rust_dispatch_trampoline`.  This is
+    /// global, and only needs to be called once.
+    pub fn duk_rust_set_dispatch(dispatch: duk_c_function);
+
+    /// Like `duk_push_c_function`, but allows `func` to return
+    /// `DUK_RET_RUST_THROW`.
+    pub fn duk_push_rust_function(
+        ctx: *mut duk_context, func: duk_c_function,
+        nargs: duk_idx_t) -> duk_idx_t;
+
+    /// Like `duk_new`, but returns an error status instead of throwing.
+    /// The constructor and `nargs` arguments are replaced by the new object
+    /// or an error.
+    pub fn duk_rust_pnew(ctx: *mut duk_context, nargs: duk_idx_t) ->
+        duk_int_t;
+
+    /// Like `duk_dump_function`, but returns an error status instead of
+    /// throwing.
+    pub fn duk_rust_pdump_function(ctx: *mut duk_context) -> duk_int_t;
+
+    /// Like `duk_load_function`, but returns an error status instead of
+    /// throwing.  Invalid bytecode may still crash the interpreter.
+    pub fn duk_rust_pload_function(ctx: *mut duk_context) -> duk_int_t;
+
+    /// Get, put, test, delete or enumerate properties, returning an error
+    /// status instead of throwing.  See glue.c for the stack layout of
+    /// each operation.
+    pub fn duk_rust_pprop(ctx: *mut duk_context, op: duk_int_t,
+                          nargs: duk_idx_t) -> duk_int_t;
+
+    /// How many objects the heap of `ctx` holds, including garbage which
+    /// hasn't been collected yet.  See internals.c.
+    pub fn duk_rust_count_objects(ctx: *mut duk_context) -> duk_size_t;
+
+    /// The `DUK_VERSION` of the duktape library we were linked with.
+    pub fn duk_rust_get_version() -> duk_int_t;
+
+    /// Set the function which duktape calls periodically while running
+    /// scripts, to decide whether they've timed out.  This is global, and
+    /// only needs to be called once.
+    pub fn duk_rust_set_exec_timeout_check(
+        check: duk_rust_exec_timeout_check_function);
 }
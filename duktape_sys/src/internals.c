@@ -0,0 +1,31 @@
+/// Duktape has no API for heap statistics, so we compile duktape.c as part
+/// of this file, which lets us look at its internal data structures.
+#include "duktape.c"
+
+/// Count the objects on the heap of `ctx` which haven't been freed yet.
+/// This includes unreachable objects which the garbage collector hasn't
+/// found, and objects waiting for their finalizers to run, but not
+/// strings or buffers.
+extern duk_size_t
+duk_rust_count_objects(duk_context *ctx)
+{
+    duk_heap *heap = ((duk_hthread *) ctx)->heap;
+    duk_heaphdr *h;
+    duk_size_t count = 0;
+
+    for (h = heap->heap_allocated; h != NULL;
+         h = DUK_HEAPHDR_GET_NEXT(heap, h)) {
+        if (DUK_HEAPHDR_GET_TYPE(h) == DUK_HTYPE_OBJECT) {
+            count++;
+        }
+    }
+#if defined(DUK_USE_MARK_AND_SWEEP)
+    for (h = heap->finalize_list; h != NULL;
+         h = DUK_HEAPHDR_GET_NEXT(heap, h)) {
+        if (DUK_HEAPHDR_GET_TYPE(h) == DUK_HTYPE_OBJECT) {
+            count++;
+        }
+    }
+#endif
+    return count;
+}
@@ -0,0 +1,71 @@
+use std::ptr::null_mut;
+use libc;
+use libc::c_void;
+
+use duktape_sys::*;
+
+use contexts::heap::HeapState;
+
+/// We store the size of each block in a header just before the memory we
+/// hand out, because duktape doesn't tell us how big a block is when it
+/// frees it.  This is large enough to keep the rest of the block aligned
+/// for any type.  The header counts towards the memory limit, along with
+/// the rest of the block.
+const HEADER_SIZE: usize = 16;
+
+/// Allocator functions for `duk_create_heap` which keep `HeapState::stats`
+/// up to date, and refuse to allocate past `HeapState::memory_limit`.
+/// These expect the heap's `udata` to be a `HeapState`.
+pub unsafe extern "C" fn rust_duk_alloc(udata: *mut c_void,
+                                        size: duk_size_t) -> *mut c_void
+{
+    let state = &mut *(udata as *mut HeapState);
+    let size = size as usize + HEADER_SIZE;
+    if !state.reserve(0, size) { return null_mut(); }
+    let block = libc::malloc(size as libc::size_t) as *mut u8;
+    if block.is_null() {
+        state.release(size);
+        return null_mut();
+    }
+    state.allocated(true);
+    *(block as *mut usize) = size;
+    block.offset(HEADER_SIZE as isize) as *mut c_void
+}
+
+/// See `rust_duk_alloc`.
+pub unsafe extern "C" fn rust_duk_realloc(udata: *mut c_void,
+                                          ptr: *mut c_void,
+                                          size: duk_size_t) -> *mut c_void
+{
+    if ptr.is_null() { return rust_duk_alloc(udata, size); }
+    if size == 0 {
+        rust_duk_free(udata, ptr);
+        return null_mut();
+    }
+
+    let state = &mut *(udata as *mut HeapState);
+    let old_block = (ptr as *mut u8).offset(-(HEADER_SIZE as isize));
+    let old_size = *(old_block as *mut usize);
+    let size = size as usize + HEADER_SIZE;
+    if !state.reserve(old_size, size) { return null_mut(); }
+    let block = libc::realloc(old_block as *mut c_void,
+                              size as libc::size_t) as *mut u8;
+    if block.is_null() {
+        // The old block is still valid, so undo our reservation.
+        state.reserve(size, old_size);
+        return null_mut();
+    }
+    state.allocated(false);
+    *(block as *mut usize) = size;
+    block.offset(HEADER_SIZE as isize) as *mut c_void
+}
+
+/// See `rust_duk_alloc`.
+pub unsafe extern "C" fn rust_duk_free(udata: *mut c_void, ptr: *mut c_void) {
+    if ptr.is_null() { return; }
+    let state = &mut *(udata as *mut HeapState);
+    let block = (ptr as *mut u8).offset(-(HEADER_SIZE as isize));
+    state.release(*(block as *mut usize));
+    state.freed();
+    libc::free(block as *mut c_void);
+}
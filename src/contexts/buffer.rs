@@ -0,0 +1,50 @@
+use std::ops::{Deref, DerefMut};
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+
+use duktape_sys::*;
+
+use Context;
+
+/// A duktape buffer which is kept on the value stack while Rust code reads
+/// or writes its contents in place.  The buffer is popped from the stack
+/// when the guard is dropped.
+pub struct BufferGuard<'a> {
+    /// We hold a mutable borrow so that nobody can push, pop or run
+    /// JavaScript code (which might resize the buffer) while we're alive.
+    ctx: &'a mut Context,
+    data: *mut u8,
+    len: usize
+}
+
+impl<'a> BufferGuard<'a> {
+    /// Wrap the buffer on top of the stack of `ctx`.  The caller must
+    /// ensure that `data` and `len` describe that buffer.
+    pub unsafe fn new(ctx: &'a mut Context, data: *mut u8, len: usize) ->
+        BufferGuard<'a>
+    {
+        BufferGuard{ctx: ctx, data: data, len: len}
+    }
+}
+
+impl<'a> Deref for BufferGuard<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Zero-length buffers may have a null data pointer.
+        if self.len == 0 { return &[]; }
+        unsafe { from_raw_parts(self.data as *const u8, self.len) }
+    }
+}
+
+impl<'a> DerefMut for BufferGuard<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        if self.len == 0 { return &mut []; }
+        unsafe { from_raw_parts_mut(self.data, self.len) }
+    }
+}
+
+impl<'a> Drop for BufferGuard<'a> {
+    fn drop(&mut self) {
+        unsafe { duk_pop(self.ctx.as_mut_ptr()); }
+    }
+}
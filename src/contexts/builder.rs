@@ -0,0 +1,129 @@
+use std::any::Any;
+
+use errors::base::*;
+use contexts::context::{Context, create_context};
+use contexts::heap::HeapState;
+
+/// Which memory allocator a duktape heap should use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Allocator {
+    /// Duktape's default allocator, which calls `malloc` directly.  This is
+    /// slightly faster, but `Context::memory_stats` is not available and
+    /// memory limits are not supported.
+    System,
+    /// Our own allocator, which keeps memory statistics and enforces
+    /// memory limits.  It adds a small header to every block, which is
+    /// counted too.  This is the default.
+    Tracking
+}
+
+/// Collects the options for a new `Context`.
+///
+/// ```
+/// use duktape::{ContextBuilder, Value};
+///
+/// let mut ctx = ContextBuilder::new()
+///     .memory_limit(16 * 1024 * 1024)
+///     .strict(true)
+///     .build()
+///     .unwrap();
+/// assert_eq!(Value::Number(3.0), ctx.eval("1 + 2").unwrap());
+/// ```
+pub struct ContextBuilder {
+    allocator: Allocator,
+    load_globals: bool,
+    state: HeapState
+}
+
+impl ContextBuilder {
+    /// Create a builder with the default options.
+    pub fn new() -> ContextBuilder {
+        ContextBuilder{allocator: Allocator::Tracking, load_globals: true,
+                       state: HeapState::new()}
+    }
+
+    /// Choose the memory allocator for the heap.
+    pub fn allocator(mut self, allocator: Allocator) -> ContextBuilder {
+        self.allocator = allocator;
+        self
+    }
+
+    /// Allow the heap to allocate at most `limit` bytes.  See
+    /// `Context::with_memory_limit`.
+    pub fn memory_limit(mut self, limit: usize) -> ContextBuilder {
+        self.state.memory_limit = Some(limit);
+        self
+    }
+
+    /// Call `handler` before aborting on a fatal error.  See
+    /// `Context::with_fatal_handler`.
+    pub fn fatal_handler<F>(mut self, handler: F) -> ContextBuilder
+        where F: FnMut(i32, &str) + 'static
+    {
+        self.state.fatal_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Should the global object contain the standard built-ins, such as
+    /// `Math` and `JSON`?  If not, scripts start with an empty global
+    /// object.  Defaults to true.
+    pub fn load_globals(mut self, load_globals: bool) -> ContextBuilder {
+        self.load_globals = load_globals;
+        self
+    }
+
+    /// Should code passed to `eval` run in strict mode?  Defaults to false.
+    pub fn strict(mut self, strict: bool) -> ContextBuilder {
+        self.state.strict = strict;
+        self
+    }
+
+    /// Store `data` with the heap, where callbacks can find it using
+    /// `Context::user_data`.
+    pub fn user_data<T: Any>(mut self, data: T) -> ContextBuilder {
+        self.state.user_data = Some(Box::new(data));
+        self
+    }
+
+    /// Create the context.
+    pub fn build(self) -> DuktapeResult<Context> {
+        if self.allocator == Allocator::System &&
+            self.state.memory_limit.is_some()
+        {
+            return Err(DuktapeError::new(
+                ErrorCode::Unsupported,
+                "memory limits require Allocator::Tracking"));
+        }
+        create_context(self.state, self.allocator, self.load_globals)
+    }
+}
+
+#[test]
+fn test_context_builder() {
+    // Strict mode forbids assigning to undeclared variables.
+    let mut ctx = ContextBuilder::new().strict(true).build().unwrap();
+    let err = ctx.eval("undeclared = 1;").unwrap_err();
+    assert_eq!(ErrorCode::Reference, err.code());
+    let mut ctx = ContextBuilder::new().build().unwrap();
+    ctx.eval("undeclared = 1;").unwrap();
+
+    // Without the default globals, the built-ins are gone.
+    let mut ctx = ContextBuilder::new().load_globals(false).build().unwrap();
+    assert_eq!("undefined", ctx.eval_as::<String>("typeof Math").unwrap());
+    assert_eq!(3.0, ctx.eval_as::<f64>("[1, 2].length + 1").unwrap());
+
+    // Only the tracking allocator, which is the default, keeps statistics.
+    let mut ctx = ContextBuilder::new().build().unwrap();
+    assert!(ctx.memory_stats().unwrap().bytes_in_use > 0);
+    let mut ctx = ContextBuilder::new().allocator(Allocator::System)
+        .build().unwrap();
+    assert_eq!(None, ctx.memory_stats());
+    assert!(ContextBuilder::new().allocator(Allocator::System)
+            .memory_limit(1024 * 1024).build().is_err());
+
+    // User data can be read back and changed.
+    let mut ctx = ContextBuilder::new().user_data(5u32).build().unwrap();
+    assert!(ctx.user_data::<String>().is_none());
+    *ctx.user_data::<u32>().unwrap() += 1;
+    assert_eq!(Some(&mut 6u32), ctx.user_data::<u32>());
+}
@@ -0,0 +1,259 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use duktape_sys::duk_rust_get_version;
+
+use errors::base::*;
+use contexts::context::Context;
+use contexts::heap::heap_state;
+use contexts::script::CompiledScript;
+
+/// An on-disk cache of compiled scripts, keyed by a hash of the file name,
+/// source code, strict mode setting, duktape version and pointer size.
+/// Scripts found in the cache are loaded from bytecode instead of being
+/// parsed again.
+///
+/// Each entry starts with a header holding the complete key, the length of
+/// the bytecode and a checksum of it.  We only load bytecode whose header
+/// matches, so hash collisions and truncated or corrupted files are simply
+/// recompiled.  Duktape itself doesn't validate bytecode, and a checksum
+/// won't stop anyone who can write to the cache on purpose, so the cache
+/// directory must still only be writable by trusted users.
+pub struct BytecodeCache {
+    dir: PathBuf
+}
+
+impl BytecodeCache {
+    /// Create a cache which stores bytecode in `dir`, creating the
+    /// directory if necessary.
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<BytecodeCache> {
+        try!(fs::create_dir_all(dir.as_ref()));
+        Ok(BytecodeCache{dir: dir.as_ref().to_path_buf()})
+    }
+
+    /// Compile `code` like `Context::compile`, using cached bytecode if we
+    /// have it, and saving the bytecode for next time if we don't.
+    /// Problems reading or writing the cache are logged, and we fall back
+    /// to compiling the source.
+    pub fn compile(&self, ctx: &mut Context, filename: &str, code: &str) ->
+        DuktapeResult<CompiledScript>
+    {
+        let key = cache_key(ctx, filename, code);
+        let path = self.dir.join(format!("{:016x}.dukbc", fnv1a(&key)));
+        match read_file(&path) {
+            Ok(entry) => {
+                match check_entry(&entry, &key) {
+                    Some(bytecode) => {
+                        match unsafe { ctx.load_bytecode(bytecode) } {
+                            Ok(script) => return Ok(script),
+                            Err(err) => {
+                                warn!("Ignoring unloadable bytecode in {}: {}",
+                                      path.display(), err);
+                            }
+                        }
+                    }
+                    None => {
+                        warn!("Ignoring invalid cache entry {}",
+                              path.display());
+                    }
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                warn!("Could not read {}: {}", path.display(), err);
+            }
+        }
+
+        let bytecode = try!(ctx.compile_to_bytecode(filename, code));
+        if let Err(err) = write_file(&path, &make_entry(&key, &bytecode)) {
+            warn!("Could not write {}: {}", path.display(), err);
+        }
+        unsafe { ctx.load_bytecode(&bytecode) }
+    }
+
+}
+
+/// Identifies cache entries, and the version of their header format.
+const MAGIC: &'static [u8] = b"duktape-rs bytecode 1\n";
+
+/// Everything which determines the bytecode for `code` compiled by `ctx`.
+/// Strict mode changes the bytecode, so it's part of the key, and we use
+/// the version of the duktape we're actually linked with.
+fn cache_key(ctx: &mut Context, filename: &str, code: &str) -> Vec<u8> {
+    let strict = unsafe { heap_state(ctx.as_mut_ptr()) }
+        .map_or(false, |state| state.strict);
+    let version = unsafe { duk_rust_get_version() };
+    let mut key = vec!();
+    key.extend_from_slice(filename.as_bytes());
+    key.push(0);
+    key.extend_from_slice(code.as_bytes());
+    key.push(0);
+    key.extend_from_slice(format!("{}/{}/{}", version, size_of::<usize>(),
+                                  strict).as_bytes());
+    key
+}
+
+/// Build a cache entry: `MAGIC`, then the length of `key`, `key` itself,
+/// the length of `bytecode` and its checksum, and finally `bytecode`.
+/// Lengths and checksums are little-endian `u64`s.
+fn make_entry(key: &[u8], bytecode: &[u8]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(MAGIC.len() + key.len() +
+                                       bytecode.len() + 24);
+    entry.extend_from_slice(MAGIC);
+    entry.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    entry.extend_from_slice(key);
+    entry.extend_from_slice(&(bytecode.len() as u64).to_le_bytes());
+    entry.extend_from_slice(&fnv1a(bytecode).to_le_bytes());
+    entry.extend_from_slice(bytecode);
+    entry
+}
+
+/// Return the bytecode in `entry` if its header is intact and was written
+/// for `key`, and the bytecode has the recorded length and checksum.
+fn check_entry<'a>(entry: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    let rest = match split_prefix(entry, MAGIC.len()) {
+        Some((magic, rest)) if magic == MAGIC => rest,
+        _ => return None
+    };
+    let (key_len, rest) = match read_u64(rest) {
+        Some(found) => found,
+        None => return None
+    };
+    if key_len != key.len() as u64 { return None; }
+    let rest = match split_prefix(rest, key.len()) {
+        Some((found, rest)) if found == key => rest,
+        _ => return None
+    };
+    let (len, rest) = match read_u64(rest) {
+        Some(found) => found,
+        None => return None
+    };
+    let (checksum, bytecode) = match read_u64(rest) {
+        Some(found) => found,
+        None => return None
+    };
+    if len == bytecode.len() as u64 && checksum == fnv1a(bytecode) {
+        Some(bytecode)
+    } else {
+        None
+    }
+}
+
+/// Split the first `len` bytes off `data`, if it's long enough.
+fn split_prefix(data: &[u8], len: usize) -> Option<(&[u8], &[u8])> {
+    if data.len() >= len { Some(data.split_at(len)) } else { None }
+}
+
+/// Read a little-endian `u64` from the start of `data`.
+fn read_u64(data: &[u8]) -> Option<(u64, &[u8])> {
+    split_prefix(data, 8).map(|(bytes, rest)| {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(bytes);
+        (u64::from_le_bytes(buf), rest)
+    })
+}
+
+/// Read all of `path`.
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = vec!();
+    try!(try!(File::open(path)).read_to_end(&mut data));
+    Ok(data)
+}
+
+/// Write `data` to `path`, using a temporary file so that other processes
+/// never see a partially-written cache entry.
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension(format!("tmp{}", process::id()));
+    {
+        let mut file = try!(File::create(&tmp));
+        try!(file.write_all(data));
+    }
+    fs::rename(&tmp, path)
+}
+
+/// The 64-bit FNV-1a hash function, which is simple, fast, and stable
+/// across Rust versions, unlike `std::hash`.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Fnv1a { Fnv1a(0xcbf29ce484222325) }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes.iter() {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 { self.0 }
+}
+
+/// The FNV-1a hash of `bytes`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = Fnv1a::new();
+    hash.write(bytes);
+    hash.finish()
+}
+
+#[test]
+fn test_bytecode() {
+    use std::env;
+    use Value;
+    use contexts::builder::ContextBuilder;
+
+    let mut ctx = Context::new().unwrap();
+    let bytecode = ctx.compile_to_bytecode("sq.js", "var x = 7; x * x")
+        .unwrap();
+    assert!(!bytecode.is_empty());
+    assert_eq!(ErrorCode::Syntax,
+               ctx.compile_to_bytecode("bad.js", "1 +").unwrap_err().code());
+
+    // Bytecode can be loaded into a different heap.
+    let mut other = Context::new().unwrap();
+    let script = unsafe { other.load_bytecode(&bytecode) }.unwrap();
+    assert_eq!(Value::Number(49.0), other.run_script(&script).unwrap());
+
+    let dir = env::temp_dir().join(format!("duktape-rs-test-{}",
+                                           process::id()));
+    let cache = BytecodeCache::new(&dir).unwrap();
+    let code = "var y = 6; y * 7";
+    let first = cache.compile(&mut ctx, "y.js", code).unwrap();
+    assert_eq!(Value::Number(42.0), ctx.run_script(&first).unwrap());
+    assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+    let second = cache.compile(&mut other, "y.js", code).unwrap();
+    assert_eq!(Value::Number(42.0), other.run_script(&second).unwrap());
+    assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+
+    // Strict heaps compile differently, so they get their own entries.
+    let mut strict = ContextBuilder::new().strict(true).build().unwrap();
+    let third = cache.compile(&mut strict, "y.js", code).unwrap();
+    assert_eq!(Value::Number(42.0), strict.run_script(&third).unwrap());
+    assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+
+    // Damaged entries are recompiled and replaced, rather than being
+    // handed to duktape.
+    let path = dir.join(format!("{:016x}.dukbc",
+                                fnv1a(&cache_key(&mut ctx, "y.js", code))));
+    let good = read_file(&path).unwrap();
+    let mut flipped = good.clone();
+    let last = flipped.len() - 1;
+    flipped[last] ^= 0xff;
+    let damaged = vec!(good[..good.len() - 1].to_vec(), flipped,
+                       b"garbage".to_vec(), vec!());
+    for bad in damaged.iter() {
+        write_file(&path, bad).unwrap();
+        let script = cache.compile(&mut ctx, "y.js", code).unwrap();
+        assert_eq!(Value::Number(42.0), ctx.run_script(&script).unwrap());
+        assert_eq!(good, read_file(&path).unwrap());
+    }
+
+    // Entries are only used for exactly the source they were written for.
+    let key = cache_key(&mut ctx, "y.js", code);
+    let entry = make_entry(&key, b"bytecode");
+    assert_eq!(Some(&b"bytecode"[..]), check_entry(&entry, &key));
+    let other_key = cache_key(&mut ctx, "y.js", "var y = 6; y * 8");
+    assert_eq!(None, check_entry(&entry, &other_key));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,6 +1,9 @@
 
-use errors::base::DuktapeResult;
+use libc::c_void;
+
+use errors::base::{DuktapeError, DuktapeResult};
 use types::Value;
+use contexts::persistent::PersistentRef;
 use duktape_sys::*;
 
 use Context;
@@ -8,3 +11,91 @@ use Context;
 /// A Rust callback which can be invoked from JavaScript.
 pub type Callback = fn (&mut Context, &[Value<'static>]) ->
     DuktapeResult<Value<'static>>;
+
+/// A Rust function which implements a whole family of JavaScript functions,
+/// registered with `Context::register_dispatched`.  It is passed the magic
+/// number of the function which was called.
+pub type Dispatcher = fn (&mut Context, i32, &[Value<'static>]) ->
+    DuktapeResult<Value<'static>>;
+
+/// A Rust closure which reads its own arguments from the duktape stack,
+/// and is passed how many there are.  `register_typed` uses these to decode
+/// arguments straight into Rust types, without building `Value`s first.
+pub type RawCallback = Box<FnMut(&mut Context, usize) ->
+    DuktapeResult<Value<'static>>>;
+
+/// A Rust function which is called with duktape's error code and message
+/// when the heap hits an unrecoverable error.  The process aborts as soon
+/// as it returns.
+pub type FatalHandler = Box<FnMut(i32, &str)>;
+
+/// Information about the current call to a Rust callback, returned by
+/// `Context::call_info`.  This lets a callback act as a method or a
+/// constructor.  The `this` value and the function object are only
+/// wrapped in `PersistentRef`s if the callback asks for them.
+pub struct CallInfo {
+    /// Was the function called using `new`?
+    pub is_constructor_call: bool,
+    /// Is the call strict?  Duktape treats all calls to native functions
+    /// as strict, so `this` is never coerced to an object.
+    pub is_strict_call: bool,
+    /// The function's magic number, which is 0 unless it was set when the
+    /// function was registered.  For functions registered with
+    /// `Context::register_dispatched`, this is the magic number passed to
+    /// the dispatcher.
+    pub magic: i32,
+    /// The heap pointer of the function being called, so that we can tell
+    /// if we're used from some other callback.
+    function_ptr: *mut c_void
+}
+
+impl CallInfo {
+    /// Describe the call whose function object has heap pointer
+    /// `function_ptr`.
+    pub fn new(is_constructor_call: bool, is_strict_call: bool, magic: i32,
+               function_ptr: *mut c_void) -> CallInfo
+    {
+        CallInfo{is_constructor_call: is_constructor_call,
+                 is_strict_call: is_strict_call, magic: magic,
+                 function_ptr: function_ptr}
+    }
+
+    /// The `this` value.  For a constructor call, this is the new object,
+    /// which is returned to JavaScript if the callback returns
+    /// `Value::Undefined`.  This may only be called from the callback
+    /// which called `Context::call_info`.
+    pub fn this(&self, ctx: &mut Context) -> DuktapeResult<PersistentRef> {
+        self.persist_current(ctx, duk_push_this)
+    }
+
+    /// The JavaScript function object which is being called.  Like
+    /// `this`, this may only be called from the callback which called
+    /// `Context::call_info`.
+    pub fn function(&self, ctx: &mut Context) ->
+        DuktapeResult<PersistentRef>
+    {
+        self.persist_current(ctx, duk_push_current_function)
+    }
+
+    /// Make a reference to the value pushed by `push`, after checking that
+    /// our call is still the current one.
+    fn persist_current(&self, ctx: &mut Context,
+                       push: unsafe extern "C" fn(*mut duk_context)) ->
+        DuktapeResult<PersistentRef>
+    {
+        unsafe {
+            let ptr = ctx.as_mut_ptr();
+            duk_push_current_function(ptr);
+            let current = duk_get_heapptr(ptr, -1);
+            duk_pop(ptr);
+            if current.is_null() || current != self.function_ptr {
+                return Err(DuktapeError::from_str(
+                    "CallInfo used outside of its callback"));
+            }
+            push(ptr);
+            let result = ctx.persist(-1);
+            duk_pop(ptr);
+            result
+        }
+    }
+}
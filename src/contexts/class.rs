@@ -0,0 +1,443 @@
+use std::any::{Any, TypeId};
+use std::ffi::CString;
+use std::mem::transmute;
+use std::ptr::null_mut;
+use libc::c_void;
+
+use duktape_sys::*;
+
+use errors::base::*;
+use types::Value;
+use contexts::context::{Context, invoke_callback, throw_error, RUST_FN_PROP,
+                        OwnedKind, attach_owned, get_owned, set_owned,
+                        finalize_owned};
+
+/// A method of a `JsClass`, which is called with the Rust value stored in
+/// `this`.
+pub type Method<T> = fn(&mut T, &mut Context, &[Value<'static>]) ->
+    DuktapeResult<Value<'static>>;
+
+/// A Rust type which can be exposed to JavaScript as a class, using
+/// `Context::register_class`.  Each JavaScript object created with `new`
+/// owns a `Self`, which is dropped when the object is garbage collected.
+///
+/// ```
+/// use duktape::{Context, DuktapeResult, JsClass, Method, Value};
+///
+/// struct Counter { count: f64 }
+///
+/// impl Counter {
+///     fn inc(&mut self, _ctx: &mut Context, _args: &[Value<'static>]) ->
+///         DuktapeResult<Value<'static>>
+///     {
+///         self.count += 1.0;
+///         Ok(Value::Number(self.count))
+///     }
+/// }
+///
+/// impl JsClass for Counter {
+///     fn class_name() -> &'static str { "Counter" }
+///
+///     fn construct(_ctx: &mut Context, args: &[Value<'static>]) ->
+///         DuktapeResult<Counter>
+///     {
+///         match args.get(0) {
+///             Some(&Value::Number(n)) => Ok(Counter{count: n}),
+///             _ => Ok(Counter{count: 0.0})
+///         }
+///     }
+///
+///     fn methods() -> Vec<(&'static str, Method<Counter>, Option<u16>)> {
+///         vec!(("inc", Counter::inc, Some(0)))
+///     }
+/// }
+///
+/// let mut ctx = Context::new().unwrap();
+/// ctx.register_class::<Counter>();
+/// assert_eq!(Value::Number(7.0),
+///            ctx.eval("var c = new Counter(5); c.inc(); c.inc()").unwrap());
+/// ```
+pub trait JsClass: Sized + 'static {
+    /// The name of the global JavaScript constructor, such as `"Counter"`.
+    fn class_name() -> &'static str;
+
+    /// Create a value from the arguments passed to the constructor.
+    fn construct(ctx: &mut Context, args: &[Value<'static>]) ->
+        DuktapeResult<Self>;
+
+    /// The methods to put on the class prototype, each given as
+    /// `(name, method, arg_count)`.
+    fn methods() -> Vec<(&'static str, Method<Self>, Option<u16>)>;
+}
+
+/// The Rust value owned by an instance.  We box it as `Any`, so methods
+/// can check they were called on the right kind of object, and box that
+/// again to get a thin pointer.
+type Instance = Box<Any>;
+
+/// The Rust values owned by instances.
+static INSTANCE: OwnedKind = OwnedKind{
+    name: "instance",
+    finalizer: Some(rust_duk_class_finalizer),
+    free: free_instance
+};
+
+/// The global stash key under which we keep the prototype for `T`.  Class
+/// names needn't be unique, so we use the `TypeId`.
+fn stash_key<T: JsClass>() -> CString {
+    let mut key = vec!(0xffu8);
+    key.extend(format!("rclass:{:?}", TypeId::of::<T>()).bytes());
+    CString::new(key).unwrap()
+}
+
+/// Push the constructor for `T`, along with its prototype.
+pub unsafe fn push_constructor<T: JsClass>(ctx: &mut Context) {
+    let ptr = ctx.as_mut_ptr();
+    duk_push_rust_function(ptr, Some(rust_duk_constructor::<T>),
+                           DUK_VARARGS);
+
+    // Build our prototype.
+    duk_push_object(ptr);
+    for (name, f, arg_count) in T::methods().into_iter() {
+        let c_arg_count =
+            arg_count.map(|n| n as duk_int_t).unwrap_or(DUK_VARARGS);
+        duk_push_rust_function(ptr, Some(rust_duk_method::<T>),
+                               c_arg_count);
+        duk_push_pointer(ptr, f as *mut c_void);
+        duk_put_prop_string(ptr, -2, RUST_FN_PROP.as_ptr());
+        let c_name = CString::new(name).unwrap();
+        duk_put_prop_string(ptr, -2, c_name.as_ptr());
+    }
+    duk_dup(ptr, -2);
+    let constructor = CString::new("constructor").unwrap();
+    duk_put_prop_string(ptr, -2, constructor.as_ptr());
+
+    // Remember the prototype, so that `push_instance` can find it.
+    duk_push_global_stash(ptr);
+    duk_dup(ptr, -2);
+    duk_put_prop_string(ptr, -2, stash_key::<T>().as_ptr());
+    duk_pop(ptr);
+
+    let prototype = CString::new("prototype").unwrap();
+    duk_put_prop_string(ptr, -2, prototype.as_ptr());
+}
+
+/// Push a new JavaScript object wrapping `value`, as if it had been
+/// created by the constructor for `T`.  Fails if `T` hasn't been
+/// registered.
+pub unsafe fn push_instance<T: JsClass>(ctx: &mut Context, value: T) ->
+    DuktapeResult<()>
+{
+    let ptr = ctx.as_mut_ptr();
+    duk_push_global_stash(ptr);
+    duk_get_prop_string(ptr, -1, stash_key::<T>().as_ptr());
+    if duk_is_object(ptr, -1) == 0 {
+        duk_pop_2(ptr);
+        return Err(DuktapeError::from_str(
+            &format!("class {} has not been registered", T::class_name())));
+    }
+    duk_push_object(ptr);
+    duk_dup(ptr, -2);
+    duk_set_prototype(ptr, -2);
+    attach(ptr, -1, value);
+    duk_remove(ptr, -2); // Remove the prototype.
+    duk_remove(ptr, -2); // Remove the stash.
+    Ok(())
+}
+
+/// Give the object at `idx` ownership of `value`, and make sure it gets
+/// dropped along with the object.
+unsafe fn attach<T: JsClass>(ptr: *mut duk_context, idx: duk_idx_t,
+                             value: T) {
+    let idx = duk_normalize_index(ptr, idx);
+    let instance: Box<Instance> = Box::new(Box::new(value));
+    attach_owned(ptr, idx, &INSTANCE, Box::into_raw(instance) as *mut c_void);
+}
+
+/// Take the Rust value away from `this` while a method runs, so that a
+/// recursive call can't create a second mutable reference to it.  Values
+/// are looked up by object, so objects which inherit from an instance
+/// don't qualify.
+unsafe fn take_this<T: JsClass>(ptr: *mut duk_context) ->
+    Result<*mut Instance, String>
+{
+    duk_push_this(ptr);
+    let owned = if duk_is_object(ptr, -1) != 0 {
+        get_owned(ptr, -1, &INSTANCE)
+    } else {
+        None
+    };
+    let result = match owned {
+        Some(p) if p.is_null() =>
+            Err(format!("{} is already in use", T::class_name())),
+        Some(p) if (*(p as *mut Instance)).is::<T>() => {
+            set_owned(ptr, -1, &INSTANCE, null_mut());
+            Ok(p as *mut Instance)
+        }
+        _ => Err(format!("this is not a {}", T::class_name()))
+    };
+    duk_pop(ptr);
+    result
+}
+
+/// The JavaScript constructor for `T`.
+unsafe extern "C" fn rust_duk_constructor<T: JsClass>(ctx: *mut duk_context)
+    -> duk_ret_t
+{
+    assert!(ctx != null_mut());
+    let mut ctx = Context::from_borrowed_mut_ptr(ctx);
+    if duk_is_constructor_call(ctx.as_mut_ptr()) == 0 {
+        let msg = format!("{} must be called with new", T::class_name());
+        return throw_error(&mut ctx, ErrorCode::Type, &msg);
+    }
+    invoke_callback(&mut ctx, |ctx, args| {
+        let value = try!(T::construct(ctx, args));
+        let ptr = ctx.as_mut_ptr();
+        duk_push_this(ptr);
+        attach(ptr, -1, value);
+        duk_pop(ptr);
+        // Returning nothing makes `new` use `this`.
+        Ok(Value::Undefined)
+    })
+}
+
+/// Calls a `Method<T>` stored in the current function.
+unsafe extern "C" fn rust_duk_method<T: JsClass>(ctx: *mut duk_context) ->
+    duk_ret_t
+{
+    assert!(ctx != null_mut());
+    let mut ctx = Context::from_borrowed_mut_ptr(ctx);
+    let ptr = ctx.as_mut_ptr();
+
+    // Our methods are always created with a pointer, but check anyway:
+    // transmuting null into a `fn` would be undefined behaviour.
+    duk_push_current_function(ptr);
+    duk_get_prop_string(ptr, -1, RUST_FN_PROP.as_ptr());
+    let f = duk_get_pointer(ptr, -1);
+    duk_pop_2(ptr);
+    if f.is_null() {
+        let msg = format!("not a method of {}", T::class_name());
+        return throw_error(&mut ctx, ErrorCode::Type, &msg);
+    }
+    let f: Method<T> = transmute(f);
+
+    let p = match take_this::<T>(ptr) {
+        Ok(p) => p,
+        Err(msg) => return throw_error(&mut ctx, ErrorCode::Type, &msg)
+    };
+    let ret = invoke_callback(&mut ctx, |ctx, args| {
+        let value = (*p).downcast_mut::<T>().unwrap();
+        f(value, ctx, args)
+    });
+
+    // Put our value back.  `this` is still on the call stack, so it can't
+    // have been finalized in the meantime.
+    duk_push_this(ptr);
+    set_owned(ptr, -1, &INSTANCE, p as *mut c_void);
+    duk_pop(ptr);
+    ret
+}
+
+/// Finalizer for objects created by `rust_duk_constructor` or
+/// `push_instance`, which drops their Rust value.
+unsafe extern "C" fn rust_duk_class_finalizer(ctx: *mut duk_context) ->
+    duk_ret_t
+{
+    finalize_owned(ctx, 0, &INSTANCE);
+    0
+}
+
+/// Drop the Rust value of a class instance.
+unsafe fn free_instance(p: *mut c_void) {
+    abort_on_panic!("unexpected panic while dropping a class instance", {
+        drop(Box::from_raw(p as *mut Instance));
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use errors::base::*;
+    use types::Value;
+    use contexts::context::Context;
+    use super::{JsClass, Method};
+
+    thread_local!(static DROPS: Cell<usize> = Cell::new(0));
+
+    pub struct Counter { count: f64 }
+
+    impl Counter {
+        fn inc(&mut self, _ctx: &mut Context, _args: &[Value<'static>]) ->
+            DuktapeResult<Value<'static>>
+        {
+            self.count += 1.0;
+            Ok(Value::Number(self.count))
+        }
+
+        fn call_back(&mut self, ctx: &mut Context,
+                     _args: &[Value<'static>]) ->
+            DuktapeResult<Value<'static>>
+        {
+            // Calling another method on ourselves must fail cleanly.
+            ctx.eval("c.inc()")
+        }
+    }
+
+    impl Drop for Counter {
+        fn drop(&mut self) { DROPS.with(|d| d.set(d.get() + 1)); }
+    }
+
+    impl JsClass for Counter {
+        fn class_name() -> &'static str { "Counter" }
+
+        fn construct(_ctx: &mut Context, args: &[Value<'static>]) ->
+            DuktapeResult<Counter>
+        {
+            match args.get(0) {
+                Some(&Value::Number(n)) => Ok(Counter{count: n}),
+                _ => Err(DuktapeError::new(ErrorCode::Type,
+                                           "expected a starting count"))
+            }
+        }
+
+        fn methods() -> Vec<(&'static str, Method<Counter>, Option<u16>)> {
+            vec!(("inc", Counter::inc, Some(0)),
+                 ("callBack", Counter::call_back, Some(0)))
+        }
+    }
+
+    #[test]
+    fn test_classes() {
+        DROPS.with(|d| d.set(0));
+        let mut ctx = Context::new().unwrap();
+        ctx.register_class::<Counter>();
+
+        assert_eq!(Value::Number(7.0),
+                   ctx.eval("var c = new Counter(5); c.inc(); c.inc()")
+                       .unwrap());
+        assert_eq!(Value::Bool(true),
+                   ctx.eval("c instanceof Counter && \
+                             c.constructor === Counter").unwrap());
+
+        // Misuse is reported as a TypeError.
+        assert_eq!(ErrorCode::Type,
+                   ctx.eval("Counter(1)").unwrap_err().code());
+        assert_eq!(ErrorCode::Type,
+                   ctx.eval("new Counter('x')").unwrap_err().code());
+        assert_eq!(ErrorCode::Type,
+                   ctx.eval("c.inc.call({})").unwrap_err().code());
+        assert_eq!(ErrorCode::Type,
+                   ctx.eval("c.callBack()").unwrap_err().code());
+        assert_eq!(ErrorCode::Type,
+                   ctx.eval("Object.create(c).inc()").unwrap_err().code());
+        assert_eq!(Value::Number(8.0), ctx.eval("c.inc()").unwrap());
+
+        // Frozen instances still work.
+        assert_eq!(Value::Number(9.0),
+                   ctx.eval("Object.freeze(c); c.inc()").unwrap());
+
+        // Instances can also be created from Rust.
+        let obj = ctx.new_instance(Counter{count: 10.0}).unwrap();
+        assert_eq!(Value::Number(11.0),
+                   ctx.call_method(obj.as_persistent(), "inc", &[]).unwrap());
+
+        // Values are dropped when their objects are collected.
+        ctx.eval("c = null;").unwrap();
+        ctx.gc();
+        assert_eq!(1, DROPS.with(|d| d.get()));
+        drop(obj);
+        drop(ctx);
+        assert_eq!(2, DROPS.with(|d| d.get()));
+    }
+
+    #[test]
+    fn test_replaced_finalizers() {
+        DROPS.with(|d| d.set(0));
+        let mut ctx = Context::new().unwrap();
+        ctx.register_class::<Counter>();
+
+        // An instance whose finalizer is replaced gives up its value.
+        assert_eq!(Value::Number(2.0),
+                   ctx.eval("var c = new Counter(1); c.inc()").unwrap());
+        assert_eq!(ErrorCode::Type,
+                   ctx.eval("Duktape.fin(c, function() {}); c.inc()")
+                       .unwrap_err().code());
+        assert_eq!(1, DROPS.with(|d| d.get()));
+
+        // Instances which are collected without our finalizer don't hand
+        // their values on to new objects at the same address.
+        ctx.eval("for (var i = 0; i < 100; i++) { \
+                      Duktape.fin(new Counter(i), function() {}); \
+                  }").unwrap();
+        ctx.gc();
+        assert_eq!(Value::Number(0.0),
+                   ctx.eval("var reused = 0; \
+                             for (var i = 0; i < 100; i++) { \
+                                 try { \
+                                     Counter.prototype.inc.call({}); \
+                                     reused++; \
+                                 } catch (e) {} \
+                             } \
+                             reused").unwrap());
+        assert_eq!(Value::Number(100.0),
+                   ctx.eval("var fresh = 0; \
+                             for (var i = 0; i < 100; i++) { \
+                                 if (new Counter(i).inc() === i + 1) { \
+                                     fresh++; \
+                                 } \
+                             } \
+                             fresh").unwrap());
+
+        // Whatever is left over is dropped along with the heap.
+        drop(ctx);
+        assert_eq!(201, DROPS.with(|d| d.get()));
+    }
+
+    /// A different type which uses the same class name as `Counter`.
+    pub struct Impostor { name: String }
+
+    impl Impostor {
+        fn rename(&mut self, _ctx: &mut Context, _args: &[Value<'static>]) ->
+            DuktapeResult<Value<'static>>
+        {
+            self.name.push('!');
+            Ok(Value::Undefined)
+        }
+    }
+
+    impl JsClass for Impostor {
+        fn class_name() -> &'static str { "Counter" }
+
+        fn construct(_ctx: &mut Context, _args: &[Value<'static>]) ->
+            DuktapeResult<Impostor>
+        {
+            Ok(Impostor{name: "impostor".to_string()})
+        }
+
+        fn methods() -> Vec<(&'static str, Method<Impostor>, Option<u16>)> {
+            vec!(("rename", Impostor::rename, Some(0)))
+        }
+    }
+
+    #[test]
+    fn test_classes_with_the_same_name() {
+        let mut ctx = Context::new().unwrap();
+        ctx.register_class::<Counter>();
+        ctx.eval("var RealCounter = Counter;").unwrap();
+        ctx.register_class::<Impostor>();
+
+        // Methods check the type, not the name.
+        assert_eq!(ErrorCode::Type,
+                   ctx.eval("Counter.prototype.rename.call(\
+                                 new RealCounter(1))").unwrap_err().code());
+        assert_eq!(ErrorCode::Type,
+                   ctx.eval("RealCounter.prototype.inc.call(new Counter())")
+                       .unwrap_err().code());
+
+        // Each type keeps its own prototype.
+        let obj = ctx.new_instance(Counter{count: 1.0}).unwrap();
+        assert_eq!(Value::Number(2.0),
+                   ctx.call_method(obj.as_persistent(), "inc", &[]).unwrap());
+    }
+}
@@ -1,8 +1,13 @@
+use std::any::Any;
+use std::collections::HashSet;
 use std::borrow::Cow;
 use std::ffi::CString;
 use std::mem::transmute;
 use std::ops::Deref;
-use std::ptr::null_mut;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+use std::time::Duration;
+use std::ptr::{null_mut, copy_nonoverlapping};
 use std::slice::from_raw_parts;
 use std::string::String;
 use std::ffi::CStr;
@@ -10,6 +15,7 @@ use std::str;
 use libc;
 use libc::c_void;
 use cesu8::{to_cesu8, from_cesu8};
+use rustc_serialize::Decodable;
 
 use types::Value;
 
@@ -17,8 +23,22 @@ use duktape_sys::*;
 use errors::base::*;
 
 use contexts::from_lstring;
+use contexts::buffer::BufferGuard;
+use contexts::persistent::PersistentRef;
+use contexts::object::ObjectRef;
+use contexts::class::{self, JsClass};
+use contexts::typed::TypedCallback;
+use contexts::script::CompiledScript;
+use contexts::heap::{HeapState, MemoryStats, heap_state,
+                     install_exec_timeout_check, register_heap,
+                     unregister_heap};
+use contexts::heap::rust_duk_fatal;
+use contexts::builder::{Allocator, ContextBuilder};
+use contexts::alloc::{rust_duk_alloc, rust_duk_realloc, rust_duk_free};
 use Callback;
+use contexts::callback::{RawCallback, CallInfo, Dispatcher};
 use io::encoder::{Encoder, DuktapeEncodable};
+use io::decoder::{Decoder, DuktapeDecodable};
 
 
 /// A duktape interpreter context.  An individual context is not
@@ -29,16 +49,28 @@ pub struct Context {
 }
 
 impl Context {
-    /// Create a new duktape context.
+    /// Create a new duktape context.  Use `ContextBuilder` for more
+    /// options.
     pub fn new() -> DuktapeResult<Context> {
-        let ptr = unsafe {
-            duk_create_heap(None, None, None, null_mut(), None)
-        };
-        if ptr.is_null() {
-            Err(DuktapeError::from_str("Could not create heap"))
-        } else {
-            Ok(Context{ptr: ptr, owned: true})
-        }
+        ContextBuilder::new().build()
+    }
+
+    /// Create a new duktape context which may allocate at most `limit`
+    /// bytes.  Allocations past the limit fail, and the `eval` or `call`
+    /// which needed them returns an error with `ErrorCode::Alloc`.
+    pub fn with_memory_limit(limit: usize) -> DuktapeResult<Context> {
+        ContextBuilder::new().memory_limit(limit).build()
+    }
+
+    /// Create a new duktape context which calls `handler` with duktape's
+    /// error code and message if the heap hits an unrecoverable error.
+    /// The process aborts once `handler` returns, so this is a chance to
+    /// flush logs or record the failure.  Without a handler, we print the
+    /// message to standard error before aborting.
+    pub fn with_fatal_handler<F>(handler: F) -> DuktapeResult<Context>
+        where F: FnMut(i32, &str) + 'static
+    {
+        ContextBuilder::new().fatal_handler(handler).build()
     }
 
     /// Create a new duktape context by wrapping an existing mutable
@@ -53,6 +85,53 @@ impl Context {
     /// unless you're implementing low-level add-ons to this library.
     pub unsafe fn as_mut_ptr(&mut self) -> *mut duk_context { self.ptr }
 
+    /// Should a panic in a Rust callback be turned into a JavaScript
+    /// `InternalError`?  By default, such panics abort the process.  When
+    /// this is enabled, the value stack is restored, the error is thrown
+    /// into the calling script, and the outermost `eval` or `call` returns
+    /// an error for which `is_panic()` is true, even if the script caught
+    /// the exception.
+    pub fn set_catch_panics(&mut self, catch_panics: bool) {
+        if let Some(state) = unsafe { heap_state(self.ptr) } {
+            state.catch_panics = catch_panics;
+        }
+    }
+
+    /// Get the data stored with `ContextBuilder::user_data` or
+    /// `set_user_data`, if it is a `T`.
+    pub fn user_data<T: Any>(&mut self) -> Option<&mut T> {
+        unsafe { heap_state(self.ptr) }
+            .and_then(|state| state.user_data.as_mut())
+            .and_then(|data| data.downcast_mut::<T>())
+    }
+
+    /// Store `data` with this context's heap, replacing any existing user
+    /// data.  It's shared by every `Context` on the heap, including the
+    /// ones passed to callbacks.
+    pub fn set_user_data<T: Any>(&mut self, data: T) {
+        if let Some(state) = unsafe { heap_state(self.ptr) } {
+            state.user_data = Some(Box::new(data));
+        }
+    }
+
+    /// Get memory usage statistics for this context's heap.  Returns
+    /// `None` unless the heap uses `Allocator::Tracking`.
+    pub fn memory_stats(&mut self) -> Option<MemoryStats> {
+        let stats = unsafe { heap_state(self.ptr) }
+            .and_then(|state| if state.tracking { Some(state.stats) }
+                              else { None });
+        stats.map(|mut stats| {
+            stats.live_objects =
+                unsafe { duk_rust_count_objects(self.ptr) } as usize;
+            stats
+        })
+    }
+
+    /// Run a full garbage collection on this context's heap.
+    pub fn gc(&mut self) {
+        unsafe { duk_gc(self.ptr, 0); }
+    }
+
     /// Debugging: Dump the interpreter context.
     #[allow(dead_code)]
     fn dump_context(&mut self) -> String {
@@ -71,6 +150,15 @@ impl Context {
     /// type.  This is a low-level, unsafe function, and you won't normally
     /// need to call it.
     unsafe fn get(&mut self, idx: duk_idx_t) -> DuktapeResult<Value<'static>> {
+        self.get_nested(idx, 0)
+    }
+
+    /// Recursive helper for `get`.  `depth` tracks how many arrays and
+    /// objects we're currently inside, so that we can refuse to follow
+    /// cyclic or very deep data structures.
+    unsafe fn get_nested(&mut self, idx: duk_idx_t, depth: usize) ->
+        DuktapeResult<Value<'static>>
+    {
         match duk_get_type(self.ptr, idx) {
             DUK_TYPE_UNDEFINED => Ok(Value::Undefined),
             DUK_TYPE_NULL => Ok(Value::Null),
@@ -86,12 +174,128 @@ impl Context {
                 let str = duk_get_lstring(self.ptr, idx, &mut len);
                 Ok(Value::String(Cow::Owned(try!(from_lstring(str, len)))))
             }
-            _ => panic!("Cannot convert duktape data type")
+            DUK_TYPE_OBJECT => {
+                if depth >= MAX_NESTING_DEPTH {
+                    return Err(DuktapeError::from_str(
+                        "Value is nested too deeply"));
+                }
+                // Each level needs an enumerator, a key and a value, and
+                // the protected read of each value needs two more.  Inside
+                // a callback, duktape only reserves a few slots.
+                if duk_check_stack(self.ptr, 5) == 0 {
+                    return Err(DuktapeError::from_str(
+                        "Not enough stack space to convert value"));
+                }
+                // Our indices will shift as we push things.
+                let idx = duk_normalize_index(self.ptr, idx);
+                if duk_is_array(self.ptr, idx) != 0 {
+                    self.get_array(idx, depth + 1)
+                } else {
+                    self.get_object(idx, depth + 1)
+                }
+            }
+            DUK_TYPE_BUFFER => {
+                let mut size: duk_size_t = 0;
+                let data = duk_get_buffer(self.ptr, idx, &mut size);
+                let bytes = if size == 0 {
+                    vec!()
+                } else {
+                    from_raw_parts(data as *const u8, size as usize).to_vec()
+                };
+                Ok(Value::Buffer(Cow::Owned(bytes)))
+            }
+            DUK_TYPE_POINTER => {
+                Ok(Value::Pointer(duk_get_pointer(self.ptr, idx)))
+            }
+            _ => Err(DuktapeError::from_str("Cannot convert duktape data type"))
+        }
+    }
+
+    /// Convert the array at (non-negative) `idx` into a `Value::Array`.
+    unsafe fn get_array(&mut self, idx: duk_idx_t, depth: usize) ->
+        DuktapeResult<Value<'static>>
+    {
+        // Scripts can set `length` to anything, so don't trust it to size
+        // our vector, and refuse to walk absurdly long (or sparse) arrays.
+        let len = duk_get_length(self.ptr, idx) as usize;
+        if len > MAX_ARRAY_LENGTH {
+            return Err(DuktapeError::from_str("Array is too long to convert"));
+        }
+        let mut elems = vec!();
+        for i in 0..len {
+            duk_dup(self.ptr, idx);
+            duk_push_uint(self.ptr, i as duk_uint_t);
+            let elem = self.get_prop_nested(depth);
+            duk_pop(self.ptr);
+            elems.push(try!(elem));
+        }
+        Ok(Value::Array(elems))
+    }
+
+    /// Convert the object at (non-negative) `idx` into a `Value::Object`,
+    /// keeping its own enumerable properties in insertion order.
+    unsafe fn get_object(&mut self, idx: duk_idx_t, depth: usize) ->
+        DuktapeResult<Value<'static>>
+    {
+        let mut props = vec!();
+        // Enumerating a proxy runs its traps, which may throw.
+        duk_dup(self.ptr, idx);
+        duk_push_uint(self.ptr, DUK_ENUM_OWN_PROPERTIES_ONLY);
+        let status = duk_rust_pprop(self.ptr, DUK_RUST_PROP_ENUM, 2);
+        if status != DUK_EXEC_SUCCESS { return Err(self.pop_error()); }
+        while duk_next(self.ptr, -1, 0) != 0 {
+            let mut len: duk_size_t = 0;
+            let str = duk_safe_to_lstring(self.ptr, -1, &mut len);
+            let key = match from_lstring(str, len) {
+                Ok(key) => key,
+                Err(err) => { duk_pop_2(self.ptr); return Err(err); }
+            };
+            // [ enum key ] -> [ enum key obj key ] -> [ enum key value ]
+            duk_dup(self.ptr, idx);
+            duk_dup(self.ptr, -2);
+            let val = self.get_prop_nested(depth);
+            duk_pop_2(self.ptr);
+            match val {
+                Ok(val) => props.push((key, val)),
+                Err(err) => { duk_pop(self.ptr); return Err(err); }
+            }
+        }
+        duk_pop(self.ptr); // Remove enumerator.
+        Ok(Value::Object(props))
+    }
+
+    /// Read a property with `[ obj key ] -> [ value ]`, and convert the
+    /// value.  Getters and proxies may throw, so the read is protected.
+    /// Either the value or the error is left on the stack.
+    unsafe fn get_prop_nested(&mut self, depth: usize) ->
+        DuktapeResult<Value<'static>>
+    {
+        let status = duk_rust_pprop(self.ptr, DUK_RUST_PROP_GET, 2);
+        if status == DUK_EXEC_SUCCESS {
+            self.get_nested(-1, depth)
+        } else {
+            Err(self.get_error())
         }
     }
 
-    /// Push a value to the call stack.
-    pub unsafe fn push_old(&mut self, val: &Value) {
+    /// Push a value to the call stack.  Fails if the value is nested more
+    /// deeply than `get` allows, or if the stack can't grow to hold it.
+    pub unsafe fn push_old(&mut self, val: &Value) -> DuktapeResult<()> {
+        self.push_nested(val, 0)
+    }
+
+    /// Recursive helper for `push_old`.
+    unsafe fn push_nested(&mut self, val: &Value, depth: usize) ->
+        DuktapeResult<()>
+    {
+        if depth > MAX_NESTING_DEPTH {
+            return Err(DuktapeError::from_str("Value is nested too deeply"));
+        }
+        // Each level needs a container, a key and a value.
+        if duk_check_stack(self.ptr, 3) == 0 {
+            return Err(DuktapeError::from_str(
+                "Not enough stack space to push value"));
+        }
         match val {
             &Value::Undefined => duk_push_undefined(self.ptr),
             &Value::Null => duk_push_null(self.ptr),
@@ -103,7 +307,40 @@ impl Context {
                 duk_push_lstring(self.ptr, buf.as_ptr() as *const i8,
                                  buf.len() as duk_size_t);
             }
+            &Value::Array(ref elems) => {
+                duk_push_array(self.ptr);
+                for (i, elem) in elems.iter().enumerate() {
+                    if let Err(err) = self.push_nested(elem, depth + 1) {
+                        duk_pop(self.ptr);
+                        return Err(err);
+                    }
+                    duk_put_prop_index(self.ptr, -2, i as duk_uarridx_t);
+                }
+            }
+            &Value::Object(ref props) => {
+                duk_push_object(self.ptr);
+                for &(ref key, ref val) in props.iter() {
+                    let key = Value::String(Cow::Borrowed(key.deref()));
+                    if let Err(err) = self.push_nested(&key, depth + 1) {
+                        duk_pop(self.ptr);
+                        return Err(err);
+                    }
+                    if let Err(err) = self.push_nested(val, depth + 1) {
+                        duk_pop_2(self.ptr);
+                        return Err(err);
+                    }
+                    duk_put_prop(self.ptr, -3);
+                }
+            }
+            &Value::Buffer(ref bytes) => {
+                let data = duk_push_fixed_buffer(self.ptr,
+                                                 bytes.len() as duk_size_t);
+                copy_nonoverlapping(bytes.as_ptr(), data as *mut u8,
+                                    bytes.len());
+            }
+            &Value::Pointer(p) => duk_push_pointer(self.ptr, p)
         }
+        Ok(())
     }
 
     /// Push an encodable value onto the call stack.  We can push any data
@@ -113,6 +350,30 @@ impl Context {
         object.duktape_encode(&mut encoder).unwrap();
     }
 
+    /// Borrow the buffer at `idx` on the value stack, so that it can be
+    /// read or modified in place without copying.  This is mostly useful
+    /// inside callbacks, where the arguments are at indices `0..args.len()`,
+    /// and especially those registered with `register_raw`, which don't
+    /// copy their arguments first.
+    /// The buffer stays referenced from the stack until the returned guard
+    /// is dropped, and the guard borrows the context so the stack can't be
+    /// modified behind its back.
+    pub fn get_buffer(&mut self, idx: duk_idx_t) ->
+        DuktapeResult<BufferGuard>
+    {
+        unsafe {
+            if duk_is_valid_index(self.ptr, idx) == 0 ||
+                duk_is_buffer(self.ptr, idx) == 0
+            {
+                return Err(DuktapeError::from_str("Expected buffer"));
+            }
+            duk_dup(self.ptr, idx);
+            let mut size: duk_size_t = 0;
+            let data = duk_get_buffer(self.ptr, -1, &mut size);
+            Ok(BufferGuard::new(self, data as *mut u8, size as usize))
+        }
+    }
+
     /// Interpret the value on the top of the stack as either a return
     /// value or an error, depending on the value of `status`.
     unsafe fn get_result(&mut self, status: duk_int_t) ->
@@ -121,13 +382,74 @@ impl Context {
         if status == DUK_EXEC_SUCCESS {
             self.get(-1)
         } else {
-            let mut len: duk_size_t = 0;
-            let str = duk_safe_to_lstring(self.ptr, -1, &mut len);
-            let msg = try!(from_lstring(str, len));
-            Err(DuktapeError::from_str(&msg))
+            Err(self.get_error())
+        }
+    }
+
+    /// Convert the error on the top of the stack into a `DuktapeError`.
+    /// JavaScript `Error` objects have their details extracted, and any
+    /// other thrown value is preserved as a `Value`.
+    unsafe fn get_error(&mut self) -> DuktapeError {
+        if duk_rust_is_error(self.ptr, -1) != 0 {
+            if let Some(name) = self.get_string_prop(-1, "name") {
+                let message = self.get_string_prop(-1, "message");
+                let file_name = self.get_string_prop(-1, "fileName");
+                let stack = self.get_string_prop(-1, "stack");
+                let line_number = {
+                    let key = "lineNumber";
+                    duk_dup_top(self.ptr);
+                    duk_push_lstring(self.ptr, key.as_ptr() as *const i8,
+                                     key.len() as duk_size_t);
+                    let status = duk_rust_pprop(self.ptr, DUK_RUST_PROP_GET, 2);
+                    let line = if status == DUK_EXEC_SUCCESS &&
+                        duk_is_number(self.ptr, -1) != 0
+                    {
+                        Some(duk_get_number(self.ptr, -1) as u32)
+                    } else {
+                        None
+                    };
+                    duk_pop(self.ptr);
+                    line
+                };
+                return err_from_js_error(name, message, file_name,
+                                         line_number, stack);
+            }
+        }
+
+        // Somebody threw something that isn't an error.  Keep a copy
+        // before we coerce it to a string in place.
+        let value = self.get(-1);
+        let mut len: duk_size_t = 0;
+        let str = duk_safe_to_lstring(self.ptr, -1, &mut len);
+        match (value, from_lstring(str, len)) {
+            (Ok(value), Ok(msg)) => err_from_js_value(value, Some(msg)),
+            (Err(_), Ok(msg)) => DuktapeError::from_str(&msg),
+            (_, Err(err)) => err
         }
     }
 
+    /// Get the string property `key` of the object at `idx`, returning
+    /// `None` if it's missing, isn't a string, or its getter throws.
+    unsafe fn get_string_prop(&mut self, idx: duk_idx_t, key: &str) ->
+        Option<String>
+    {
+        duk_dup(self.ptr, idx);
+        duk_push_lstring(self.ptr, key.as_ptr() as *const i8,
+                         key.len() as duk_size_t);
+        let status = duk_rust_pprop(self.ptr, DUK_RUST_PROP_GET, 2);
+        let result = if status == DUK_EXEC_SUCCESS &&
+            duk_is_string(self.ptr, -1) != 0
+        {
+            let mut len: duk_size_t = 0;
+            let str = duk_get_lstring(self.ptr, -1, &mut len);
+            from_lstring(str, len).ok()
+        } else {
+            None
+        };
+        duk_pop(self.ptr);
+        result
+    }
+
     /// Given the status code returned by a duktape exec function, pop
     /// either a value or an error from the stack, convert it, and return
     /// it.
@@ -139,6 +461,78 @@ impl Context {
         result
     }
 
+    /// Pop the error on top of the stack, and convert it to a
+    /// `DuktapeError`.
+    pub unsafe fn pop_error(&mut self) -> DuktapeError {
+        let err = self.get_error();
+        duk_pop(self.ptr);
+        err
+    }
+
+    /// Like `pop_result`, but decode a successful result as a `T` instead
+    /// of converting it to a `Value`.
+    pub unsafe fn pop_decoded<T: DuktapeDecodable>(&mut self,
+                                                   status: duk_int_t) ->
+        DuktapeResult<T>
+    {
+        if status == DUK_EXEC_SUCCESS {
+            // The decoder pops the value for us.
+            let mut decoder = Decoder::new(self.ptr);
+            Decodable::decode(&mut decoder)
+        } else {
+            let err = self.get_error();
+            duk_pop(self.ptr);
+            Err(err)
+        }
+    }
+
+    /// Limit how long each call to `eval` or `call` may run.  When the
+    /// deadline passes, the script is interrupted with a `RangeError`, and
+    /// the outermost `eval` or `call` returns an error for which
+    /// `is_timeout()` is true.  Calls made from inside callbacks share the
+    /// deadline of the outermost call.
+    pub fn set_deadline(&mut self, timeout: Duration) {
+        if let Some(state) = unsafe { heap_state(self.ptr) } {
+            state.timeout = Some(timeout);
+        }
+    }
+
+    /// Limit how many bytecode instructions each call to `eval` or `call`
+    /// may execute, with the same effect as `set_deadline`.  Duktape only
+    /// checks every `INSTRUCTIONS_PER_CHECK` instructions or so, so the
+    /// budget is rounded up to a multiple of that.
+    pub fn set_instruction_budget(&mut self, instructions: u64) {
+        if let Some(state) = unsafe { heap_state(self.ptr) } {
+            state.instruction_budget = Some(instructions);
+        }
+    }
+
+    /// Remove any limits set by `set_deadline` and
+    /// `set_instruction_budget`.
+    pub fn clear_limits(&mut self) {
+        if let Some(state) = unsafe { heap_state(self.ptr) } {
+            state.timeout = None;
+            state.instruction_budget = None;
+        }
+    }
+
+    /// Run `f`, which executes JavaScript code, keeping track of how deeply
+    /// nested we are.  When the outermost call returns, report any panic
+    /// or timeout which happened along the way.  Low-level add-ons which
+    /// call into JavaScript should wrap those calls in this.
+    pub unsafe fn run<T, F>(&mut self, f: F) -> DuktapeResult<T>
+        where F: FnOnce(&mut Context) -> DuktapeResult<T>
+    {
+        if let Some(state) = heap_state(self.ptr) { state.enter(); }
+        let result = f(self);
+        if let Some(state) = heap_state(self.ptr) {
+            if let Some(err) = state.exit(result.as_ref().err()) {
+                return Err(err);
+            }
+        }
+        result
+    }
+
     /// Evaluate JavaScript source code and return the result.
     pub fn eval(&mut self, code: &str) -> DuktapeResult<Value<'static>> {
         self.eval_from("<eval>", code)
@@ -151,201 +545,1986 @@ impl Context {
     {
         unsafe {
             assert_stack_height_unchanged!(self, {
-                // Push our filename parameter and evaluate our code.
-                duk_push_lstring(self.ptr, filename.as_ptr() as *const i8,
-                                 filename.len() as duk_size_t);
-                let status = duk_eval_raw(self.ptr, code.as_ptr() as *const i8,
-                                          code.len() as duk_size_t,
-                                          DUK_COMPILE_EVAL |
-                                          DUK_COMPILE_NOSOURCE |
-                                          DUK_COMPILE_SAFE);
-                self.pop_result(status)
+                self.run(|ctx| {
+                    let status = ctx.eval_raw(filename, code);
+                    ctx.pop_result(status)
+                })
             })
         }
     }
 
-    /// Call the global JavaScript function named `fn_name` with `args`, and
-    /// return the result.
-    pub fn call(&mut self, fn_name: &str, args: &[&DuktapeEncodable]) ->
-        DuktapeResult<Value<'static>>
+    /// Evaluate JavaScript source code and decode the result as a `T`.
+    pub fn eval_as<T: DuktapeDecodable>(&mut self, code: &str) ->
+        DuktapeResult<T>
+    {
+        self.eval_from_as("<eval>", code)
+    }
+
+    /// Evaluate JavaScript source code and decode the result as a `T`.
+    /// The `filename` parameter will be used in any error messages.
+    pub fn eval_from_as<T: DuktapeDecodable>(&mut self, filename: &str,
+                                             code: &str) -> DuktapeResult<T>
     {
         unsafe {
             assert_stack_height_unchanged!(self, {
-                duk_push_global_object(self.ptr);
-                let c_str = CString::new(fn_name).unwrap();
-                duk_get_prop_string(self.ptr, -1, c_str.as_ptr());
-                {
-                    let mut encoder = Encoder::new(self.ptr);
-                    for arg in args.iter() {
-                        (*arg).duktape_encode(&mut encoder).unwrap();
-                    }
-                }
-                let status = duk_pcall(self.ptr, args.len() as i32);
-                let result = self.pop_result(status);
-                duk_pop(self.ptr); // Remove global object.
-                result
+                self.run(|ctx| {
+                    let status = ctx.eval_raw(filename, code);
+                    ctx.pop_decoded(status)
+                })
             })
         }
     }
 
-    /// Register a Rust callback as a global JavaScript function.
-    pub fn register(&mut self, fn_name: &str, f: Callback,
-                    arg_count: Option<u16>) {
-        let c_arg_count =
-            arg_count.map(|n| n as duk_int_t).unwrap_or(DUK_VARARGS);
+    /// Set the global variable `name` to `value`.  Unlike building source
+    /// code for `eval`, this can't be used to inject code.
+    pub fn set_global<T: DuktapeEncodable>(&mut self, name: &str, value: &T) ->
+        DuktapeResult<()>
+    {
         unsafe {
             assert_stack_height_unchanged!(self, {
-                // Push our global context and a pointer to our standard
-                // wrapper function.
-                duk_push_global_object(self.ptr);
-                duk_push_c_function(self.ptr,
-                                    Some(rust_duk_callback),
-                                    c_arg_count);
-
-                // Store `f` as a hidden property in our function.
-                duk_push_pointer(self.ptr, f as *mut c_void);
-                duk_put_prop_string(self.ptr, -2, RUST_FN_PROP.as_ptr());
+                self.run(|ctx| {
+                    ctx.push_global_key(name);
+                    value.duktape_encode(&mut Encoder::new(ctx.ptr)).unwrap();
+                    let status = duk_rust_pprop(ctx.ptr, DUK_RUST_PROP_PUT, 3);
+                    ctx.pop_result(status).map(|_| ())
+                })
+            })
+        }
+    }
 
-                // Store our function in a global property.
-                let c_str = CString::new(fn_name).unwrap();
-                duk_put_prop_string(self.ptr, -2, c_str.as_ptr());
-                duk_pop(self.ptr);
+    /// Get the global variable `name`, and decode it as a `T`.
+    pub fn get_global<T: DuktapeDecodable>(&mut self, name: &str) ->
+        DuktapeResult<T>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    ctx.push_global_key(name);
+                    let status = duk_rust_pprop(ctx.ptr, DUK_RUST_PROP_GET, 2);
+                    ctx.pop_decoded(status)
+                })
             })
         }
     }
-}
 
-impl Drop for Context {
-  fn drop(&mut self) {
-      if self.owned {
-          unsafe { duk_destroy_heap(self.ptr); }
-      }
-  }
-}
+    /// Is there a global variable named `name`?
+    pub fn has_global(&mut self, name: &str) -> DuktapeResult<bool> {
+        self.global_bool_op(name, DUK_RUST_PROP_HAS)
+    }
 
-/// A "internal" property key used for storing Rust function pointers, which
-/// can't be accessed from JavaScript without a lot of trickery.
-const RUST_FN_PROP: [i8; 5] = [-1, 'r' as i8, 'f' as i8, 'n' as i8, 0];
+    /// Delete the global variable `name`.  Returns false if it can't be
+    /// deleted, for example because it was declared with `var`.  See
+    /// `ObjectRef::delete`.
+    pub fn delete_global(&mut self, name: &str) -> DuktapeResult<bool> {
+        self.global_bool_op(name, DUK_RUST_PROP_DEL)
+    }
 
-/// Our generic callback function.
-unsafe extern "C" fn rust_duk_callback(ctx: *mut duk_context) -> duk_ret_t {
-    // ERROR-HANDLING NOTE: Try to avoid any Rust panics or duktape unwinds
-    // inside this function.  They sort-of work--at least well enough to
-    // debug this crate--but they probably corrupt at least one of the two
-    // heaps.
+    /// Push the global object followed by `name`.
+    unsafe fn push_global_key(&mut self, name: &str) {
+        duk_push_global_object(self.ptr);
+        duk_push_lstring(self.ptr, name.as_ptr() as *const i8,
+                         name.len() as duk_size_t);
+    }
 
-    // Here, we create a mutable Context pointing into an existing duktape
-    // heap.  But this is theoretically safe, because the only way to
-    // invoke JavaScript code is to use a mutable context while calling
-    // into C.  So this is really an indirect mutable borrow.
-    assert!(ctx != null_mut());
-    let mut ctx = Context::from_borrowed_mut_ptr(ctx);
-    //println!("In callback: {}", ctx.dump_context());
+    /// Run a `duk_rust_pprop` operation on a global which returns a
+    /// boolean.
+    fn global_bool_op(&mut self, name: &str, op: duk_int_t) ->
+        DuktapeResult<bool>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    ctx.push_global_key(name);
+                    let status = duk_rust_pprop(ctx.ptr, op, 2);
+                    match try!(ctx.pop_result(status)) {
+                        Value::Bool(b) => Ok(b),
+                        _ => Err(DuktapeError::from_str("Expected a boolean"))
+                    }
+                })
+            })
+        }
+    }
 
-    // Recover our Rust function pointer.
-    let f: Callback = assert_stack_height_unchanged!(ctx, {
-        duk_push_current_function(ctx.ptr);
-        duk_get_prop_string(ctx.ptr, -1, RUST_FN_PROP.as_ptr());
-        let p = duk_get_pointer(ctx.ptr, -1);
-        duk_pop_n(ctx.ptr, 2);
-        assert!(p != null_mut());
-        transmute(p)
-    });
+    /// Get a reference to the global object.
+    pub fn global_object(&mut self) -> DuktapeResult<ObjectRef> {
+        let r = unsafe {
+            duk_push_global_object(self.ptr);
+            let r = self.persist(-1);
+            duk_pop(self.ptr);
+            try!(r)
+        };
+        ObjectRef::from_ref(self, r)
+    }
 
-    // Coerce our arguments to Rust values.
-    let arg_count = duk_get_top(ctx.ptr) as usize;
-    let mut args = Vec::with_capacity(arg_count);
-    for i in 0..arg_count {
-        match ctx.get(i as duk_idx_t) {
-            Ok(arg) => args.push(arg),
-            // Can't convert argument to Rust.
-            // TODO: Need testcase.
-            Err(_) => return DUK_RET_TYPE_ERROR
-        }
+    /// Create a new, empty JavaScript object, and return a reference to it.
+    pub fn new_object(&mut self) -> DuktapeResult<ObjectRef> {
+        let r = unsafe {
+            duk_push_object(self.ptr);
+            let r = self.persist(-1);
+            duk_pop(self.ptr);
+            try!(r)
+        };
+        ObjectRef::from_ref(self, r)
     }
-    //println!("args: {}", args);
 
-    // Call our function.
-    let result =
-        abort_on_panic!("unexpected panic in code called from JavaScript", {
-            f(&mut ctx, &args)
-        });
+    /// Evaluate JavaScript source code and keep a persistent reference to
+    /// the result, so that it can be used after this call returns.
+    pub fn eval_to_ref(&mut self, code: &str) -> DuktapeResult<PersistentRef> {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    let status = ctx.eval_raw("<eval>", code);
+                    let result = if status == DUK_EXEC_SUCCESS {
+                        ctx.persist(-1)
+                    } else {
+                        Err(ctx.get_error())
+                    };
+                    duk_pop(ctx.ptr);
+                    result
+                })
+            })
+        }
+    }
 
-    // Return our result.
-    match result {
-        // No return value.
-        Ok(Value::Undefined) => { 0 }
-        // A single return value.
-        Ok(ref val) => { ctx.push_old(val); 1 }
-        Err(ref err) => {
-            let code = err_code(err) as duk_int_t;
-            match err_message(err) {
-                // An error with an actual error message.
-                &Some(ref _msg) => {
-                    // The following would more-or-less work, but it
-                    // performs a non-local exit from a Rust function using
-                    // C APIs, which is a Bad Idea.
-                    //to_cesu8(&msg[]).with_c_str(|c_str| {
-                    //    duk_push_error_object_string(ctx.ptr, code,
-                    //                                 file!().as_ptr()
-                    //                                     as *const i8,
-                    //                                 line!() as i32,
-                    //                                 c_str as *const i8);
-                    //});
-                    //duk_throw(ctx.ptr);
-                    //-1
-                    DUK_RET_ERROR
-                }
-                // A generic error using one of the standard codes.
-                &None => { -code }
+    /// Keep a persistent reference to the value at `idx` on the stack.
+    /// Inside a callback, the arguments are at indices `0` through `n-1`,
+    /// so this can be used to hold on to a JavaScript function passed in by
+    /// a script.
+    pub fn persist(&mut self, idx: duk_idx_t) -> DuktapeResult<PersistentRef> {
+        unsafe {
+            if duk_is_valid_index(self.ptr, idx) == 0 {
+                return Err(DuktapeError::from_str("Invalid stack index"));
             }
+            PersistentRef::new(self.ptr, idx).ok_or_else(|| {
+                DuktapeError::from_str("Heap does not support references")
+            })
         }
     }
-}
 
-#[test]
-fn test_eval() {
-    let mut ctx = Context::new().unwrap();
-    assert_eq!(Value::Undefined, ctx.eval("undefined").unwrap());
-    assert_eq!(Value::Null, ctx.eval("null").unwrap());
-    assert_eq!(Value::Bool(true), ctx.eval("true").unwrap());
-    assert_eq!(Value::Bool(false), ctx.eval("false").unwrap());
-    assert_eq!(Value::Number(5.0), ctx.eval("2 + 3").unwrap());
+    /// Push the value held by `r` onto the stack.  The reference must
+    /// belong to this context's heap; use `get_ref` for a checked version.
+    pub unsafe fn push_ref(&mut self, r: &PersistentRef) {
+        r.push(self.ptr);
+    }
+
+    /// Get the value held by `r`, converted to a Rust value.
+    pub fn get_ref(&mut self, r: &PersistentRef) ->
+        DuktapeResult<Value<'static>>
+    {
+        unsafe {
+            try!(self.check_ref(r));
+            self.push_ref(r);
+            let result = self.get(-1);
+            duk_pop(self.ptr);
+            result
+        }
+    }
 
-    let result = ctx.eval("'é'").unwrap();
-    let expected = Value::String(Cow::Borrowed("é"));
-    // result.woot();
-    // expected.woot();
+    /// Make sure `r` can safely be pushed onto our stack, because it
+    /// belongs to this context's heap and that heap is still alive.
+    pub fn check_ref(&mut self, r: &PersistentRef) -> DuktapeResult<()> {
+        if !r.is_alive() || !unsafe { r.belongs_to(self.ptr) } {
+            Err(DuktapeError::from_str("Reference belongs to another heap"))
+        } else {
+            Ok(())
+        }
+    }
 
-    println!("expected: {:?}",expected);
-    println!("result: {:?}", result);
-    // FIXME: un comment out the tests
-    // assert_eq!(expected, result);
-}
+    /// Evaluate `code`, leaving either the result or an error on the
+    /// stack, and return the status.
+    unsafe fn eval_raw(&mut self, filename: &str, code: &str) -> duk_int_t {
+        // Push our filename parameter and evaluate our code.
+        duk_push_lstring(self.ptr, filename.as_ptr() as *const i8,
+                         filename.len() as duk_size_t);
+        duk_eval_raw(self.ptr, code.as_ptr() as *const i8,
+                     code.len() as duk_size_t,
+                     DUK_COMPILE_EVAL |
+                     DUK_COMPILE_NOSOURCE |
+                     DUK_COMPILE_SAFE |
+                     self.strict_flag())
+    }
 
-#[test]
-fn test_unicode_supplementary_planes() {
-    // Pay careful attention to characters U+10000 and greater, because
-    // duktape uses CESU-8 internally, which isn't _quite_ valid UTF-8.
-    // This is thanks to the fact that JavaScript uses 16-bit characters
-    // and allows manipulating invalid UTF-16 data with mismatched
-    // surrogate pairs.
-    let mut ctx = Context::new().unwrap();
+    /// `DUK_COMPILE_STRICT` if this heap compiles code in strict mode, or
+    /// 0 otherwise.
+    unsafe fn strict_flag(&mut self) -> duk_uint_t {
+        match heap_state(self.ptr) {
+            Some(ref state) if state.strict => DUK_COMPILE_STRICT,
+            _ => 0
+        }
+    }
 
-    // FIXME: un comment out the tests
-    // assert_eq!(Value::String(Cow::Borrowed("𓀀")), ctx.eval("'𓀀'").unwrap());
-    // assert_eq!(Value::String(Cow::Borrowed("𓀀")),
-               // ctx.eval("'\\uD80C\\uDC00'").unwrap());
+    /// Compile JavaScript source code without running it.  Running the
+    /// result with `run_script` behaves like calling `eval_from`, but the
+    /// code is only parsed once.
+    pub fn compile(&mut self, filename: &str, code: &str) ->
+        DuktapeResult<CompiledScript>
+    {
+        self.compile_raw(filename, code, DUK_COMPILE_EVAL)
+    }
 
-    ctx.eval("function id(x) { return x; }").unwrap();
-    // assert_eq!(Ok(Value::String(Cow::Borrowed("𓀀"))),
-               // ctx.call("id", &[&"𓀀"]));
-}
+    /// Compile a single JavaScript function expression, such as
+    /// `"function (a, b) { return a + b; }"`, which can then be called
+    /// with arguments using `call_script`.
+    pub fn compile_function(&mut self, filename: &str, code: &str) ->
+        DuktapeResult<CompiledScript>
+    {
+        self.compile_raw(filename, code, DUK_COMPILE_FUNCTION)
+    }
 
-#[test]
-fn test_eval_errors() {
-    let mut ctx = Context::new().unwrap();
-    assert_eq!(true, ctx.eval("3 +").is_err());
+    /// Compile `code` with the specified `duk_compile_raw` flags, and keep
+    /// a reference to the resulting function.
+    fn compile_raw(&mut self, filename: &str, code: &str,
+                   flags: duk_uint_t) -> DuktapeResult<CompiledScript>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                let status = self.compile_on_stack(filename, code, flags);
+                let result = if status == DUK_EXEC_SUCCESS {
+                    self.persist(-1).map(CompiledScript::new)
+                } else {
+                    Err(self.get_error())
+                };
+                duk_pop(self.ptr);
+                result
+            })
+        }
+    }
+
+    /// Compile `code` with the specified `duk_compile_raw` flags, leaving
+    /// either the function or an error on the stack, and return the
+    /// status.
+    unsafe fn compile_on_stack(&mut self, filename: &str, code: &str,
+                               flags: duk_uint_t) -> duk_int_t {
+        duk_push_lstring(self.ptr, filename.as_ptr() as *const i8,
+                         filename.len() as duk_size_t);
+        duk_compile_raw(self.ptr, code.as_ptr() as *const i8,
+                        code.len() as duk_size_t,
+                        flags |
+                        DUK_COMPILE_NOSOURCE |
+                        DUK_COMPILE_SAFE |
+                        self.strict_flag())
+    }
+
+    /// Compile JavaScript source code like `compile`, and return it as
+    /// bytecode which can be saved and passed to `load_bytecode` later.
+    /// Bytecode is specific to the duktape version and platform.
+    pub fn compile_to_bytecode(&mut self, filename: &str, code: &str) ->
+        DuktapeResult<Vec<u8>>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                let mut status =
+                    self.compile_on_stack(filename, code, DUK_COMPILE_EVAL);
+                if status == DUK_EXEC_SUCCESS {
+                    status = duk_rust_pdump_function(self.ptr);
+                }
+                let result = if status == DUK_EXEC_SUCCESS {
+                    let mut size: duk_size_t = 0;
+                    let data = duk_get_buffer(self.ptr, -1, &mut size);
+                    if size == 0 {
+                        Ok(vec!())
+                    } else {
+                        Ok(from_raw_parts(data as *const u8,
+                                          size as usize).to_vec())
+                    }
+                } else {
+                    Err(self.get_error())
+                };
+                duk_pop(self.ptr);
+                result
+            })
+        }
+    }
+
+    /// Load bytecode created by `compile_to_bytecode`, so that it can be
+    /// run with `run_script`.  This is unsafe because duktape doesn't
+    /// validate bytecode: Loading corrupt or malicious bytecode, or
+    /// bytecode from another duktape version, may crash the process.
+    pub unsafe fn load_bytecode(&mut self, bytecode: &[u8]) ->
+        DuktapeResult<CompiledScript>
+    {
+        assert_stack_height_unchanged!(self, {
+            let buf = duk_push_fixed_buffer(self.ptr,
+                                            bytecode.len() as duk_size_t);
+            copy_nonoverlapping(bytecode.as_ptr(), buf as *mut u8,
+                                bytecode.len());
+            let status = duk_rust_pload_function(self.ptr);
+            let result = if status == DUK_EXEC_SUCCESS {
+                self.persist(-1).map(CompiledScript::new)
+            } else {
+                Err(self.get_error())
+            };
+            duk_pop(self.ptr);
+            result
+        })
+    }
+
+    /// Run a script compiled with `compile`, and return the result.  As
+    /// with `eval`, `this` is the global object.
+    pub fn run_script(&mut self, script: &CompiledScript) ->
+        DuktapeResult<Value<'static>>
+    {
+        try!(self.check_ref(script.function()));
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    ctx.push_ref(script.function());
+                    duk_push_global_object(ctx.ptr);
+                    let status = duk_pcall_method(ctx.ptr, 0);
+                    ctx.pop_result(status)
+                })
+            })
+        }
+    }
+
+    /// Call a function compiled with `compile_function` with `args`, and
+    /// return the result.
+    pub fn call_script(&mut self, script: &CompiledScript,
+                       args: &[&DuktapeEncodable]) ->
+        DuktapeResult<Value<'static>>
+    {
+        self.call_ref(script.function(), args)
+    }
+
+    /// Call the global JavaScript function named `fn_name` with `args`, and
+    /// return the result.
+    pub fn call(&mut self, fn_name: &str, args: &[&DuktapeEncodable]) ->
+        DuktapeResult<Value<'static>>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    let status = ctx.call_raw(fn_name, args);
+                    ctx.pop_result(status)
+                })
+            })
+        }
+    }
+
+    /// Call the global JavaScript function named `fn_name` with `args`, and
+    /// decode the result as a `T`.
+    pub fn call_as<T: DuktapeDecodable>(&mut self, fn_name: &str,
+                                        args: &[&DuktapeEncodable]) ->
+        DuktapeResult<T>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    let status = ctx.call_raw(fn_name, args);
+                    ctx.pop_decoded(status)
+                })
+            })
+        }
+    }
+
+    /// Call the global function `fn_name`, leaving either the result or an
+    /// error on the stack, and return the status.
+    unsafe fn call_raw(&mut self, fn_name: &str,
+                       args: &[&DuktapeEncodable]) -> duk_int_t
+    {
+        self.push_global_key(fn_name);
+        let status = duk_rust_pprop(self.ptr, DUK_RUST_PROP_GET, 2);
+        if status != DUK_EXEC_SUCCESS { return status; }
+        self.push_args(args);
+        duk_pcall(self.ptr, args.len() as i32)
+    }
+
+    /// Push each of `args` onto the stack.
+    unsafe fn push_args(&mut self, args: &[&DuktapeEncodable]) {
+        let mut encoder = Encoder::new(self.ptr);
+        for arg in args.iter() {
+            (*arg).duktape_encode(&mut encoder).unwrap();
+        }
+    }
+
+    /// Look up a dotted `path` like `"app.handlers.onMessage"`, starting
+    /// from the global object, and push the value we find on top of the
+    /// object we found it in.  On error, the stack is left unchanged.
+    unsafe fn push_path(&mut self, path: &str) -> DuktapeResult<()> {
+        duk_push_global_object(self.ptr);
+        duk_push_global_object(self.ptr);
+        let mut seen = 0;
+        for name in path.split('.') {
+            if duk_is_null_or_undefined(self.ptr, -1) != 0 {
+                duk_pop_2(self.ptr);
+                return Err(DuktapeError::new(
+                    ErrorCode::Type,
+                    &format!("{} is null or undefined", &path[..seen - 1])));
+            }
+            duk_dup_top(self.ptr);
+            duk_push_lstring(self.ptr, name.as_ptr() as *const i8,
+                             name.len() as duk_size_t);
+            let status = duk_rust_pprop(self.ptr, DUK_RUST_PROP_GET, 2);
+            if status != DUK_EXEC_SUCCESS {
+                let err = self.pop_error();
+                duk_pop_2(self.ptr);
+                return Err(err);
+            }
+            duk_remove(self.ptr, -3); // Remove the grandparent.
+            seen += name.len() + 1;
+        }
+        Ok(())
+    }
+
+    /// Call the function at the dotted `path`, such as
+    /// `"app.handlers.onMessage"`, with `this` set to the object containing
+    /// it, and return the result.
+    pub fn call_path(&mut self, path: &str, args: &[&DuktapeEncodable]) ->
+        DuktapeResult<Value<'static>>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    try!(ctx.push_path(path));
+                    duk_swap_top(ctx.ptr, -2);
+                    ctx.push_args(args);
+                    let status =
+                        duk_pcall_method(ctx.ptr, args.len() as duk_idx_t);
+                    ctx.pop_result(status)
+                })
+            })
+        }
+    }
+
+    /// Call the function held by `f` with `args`, and return the result.
+    /// `this` will be undefined.
+    pub fn call_ref(&mut self, f: &PersistentRef,
+                    args: &[&DuktapeEncodable]) ->
+        DuktapeResult<Value<'static>>
+    {
+        try!(self.check_ref(f));
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    ctx.push_ref(f);
+                    ctx.push_args(args);
+                    let status = duk_pcall(ctx.ptr, args.len() as duk_idx_t);
+                    ctx.pop_result(status)
+                })
+            })
+        }
+    }
+
+    /// Call the method named `method` on the object held by `this`, and
+    /// return the result.
+    pub fn call_method(&mut self, this: &PersistentRef, method: &str,
+                       args: &[&DuktapeEncodable]) ->
+        DuktapeResult<Value<'static>>
+    {
+        try!(self.check_ref(this));
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    ctx.push_ref(this);
+                    let obj_idx = duk_get_top(ctx.ptr) - 1;
+                    duk_push_lstring(ctx.ptr, method.as_ptr() as *const i8,
+                                     method.len() as duk_size_t);
+                    ctx.push_args(args);
+                    let status = duk_pcall_prop(ctx.ptr, obj_idx,
+                                                args.len() as duk_idx_t);
+                    let result = ctx.pop_result(status);
+                    duk_pop(ctx.ptr); // Remove `this`.
+                    result
+                })
+            })
+        }
+    }
+
+    /// Call the constructor at the dotted `path` using `new`, and return
+    /// the object it creates.
+    pub fn construct(&mut self, path: &str, args: &[&DuktapeEncodable]) ->
+        DuktapeResult<Value<'static>>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    try!(ctx.push_path(path));
+                    duk_remove(ctx.ptr, -2); // Remove the parent object.
+                    ctx.push_args(args);
+                    let status =
+                        duk_rust_pnew(ctx.ptr, args.len() as duk_idx_t);
+                    ctx.pop_result(status)
+                })
+            })
+        }
+    }
+
+    /// Register a Rust callback as a global JavaScript function.  If the
+    /// global can't be set, for example because a script made it
+    /// read-only, we log a warning; use `register_at` to get the error.
+    pub fn register(&mut self, fn_name: &str, f: Callback,
+                    arg_count: Option<u16>) {
+        if let Err(err) = self.register_in("", fn_name, f, arg_count) {
+            warn!("Could not register {}: {}", fn_name, err);
+        }
+    }
+
+    /// Register the Rust type `T` as a global JavaScript class.  See
+    /// `JsClass` for an example.
+    pub fn register_class<T: JsClass>(&mut self) {
+        let result = unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    duk_push_global_object(ctx.ptr);
+                    class::push_constructor::<T>(ctx);
+                    let result = ctx.put_prop(T::class_name());
+                    duk_pop(ctx.ptr);
+                    result
+                })
+            })
+        };
+        if let Err(err) = result {
+            warn!("Could not register {}: {}", T::class_name(), err);
+        }
+    }
+
+    /// Wrap `value` in a new JavaScript object, just as if a script had
+    /// called the class constructor.  `T` must already be registered with
+    /// `register_class`.
+    pub fn new_instance<T: JsClass>(&mut self, value: T) ->
+        DuktapeResult<ObjectRef>
+    {
+        let r = unsafe {
+            try!(class::push_instance(self, value));
+            let r = self.persist(-1);
+            duk_pop(self.ptr);
+            try!(r)
+        };
+        ObjectRef::from_ref(self, r)
+    }
+
+    /// Register a Rust callback at a dotted `path` like
+    /// `"app.fs.readText"`, creating any missing objects along the way.
+    /// Fails if part of the path exists but isn't an object.
+    pub fn register_at(&mut self, path: &str, f: Callback,
+                       arg_count: Option<u16>) -> DuktapeResult<()>
+    {
+        let (parent, name) = match path.rfind('.') {
+            Some(i) => (&path[..i], &path[i+1..]),
+            None => ("", path)
+        };
+        self.register_in(parent, name, f, arg_count)
+    }
+
+    /// Register a Rust callback as the property `name` of the object at
+    /// the dotted `parent` path.
+    fn register_in(&mut self, parent: &str, name: &str, f: Callback,
+                   arg_count: Option<u16>) -> DuktapeResult<()>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    try!(ctx.push_namespace(parent));
+                    ctx.push_callback(f, arg_count);
+                    let result = ctx.put_prop(name);
+                    duk_pop(ctx.ptr);
+                    result
+                })
+            })
+        }
+    }
+
+    /// Push a JavaScript function which calls `f`.
+    unsafe fn push_callback(&mut self, f: Callback, arg_count: Option<u16>) {
+        let c_arg_count =
+            arg_count.map(|n| n as duk_int_t).unwrap_or(DUK_VARARGS);
+        duk_push_rust_function(self.ptr, Some(rust_duk_callback),
+                               c_arg_count);
+
+        // Store `f` as a hidden property in our function.
+        duk_push_pointer(self.ptr, f as *mut c_void);
+        duk_put_prop_string(self.ptr, -2, RUST_FN_PROP.as_ptr());
+    }
+
+    /// Pop the value on top of the stack, and store it as the property
+    /// `name` of the object below it.  The object may belong to a script,
+    /// and setters or read-only properties may throw, so this is
+    /// protected.
+    unsafe fn put_prop(&mut self, name: &str) -> DuktapeResult<()> {
+        // [ obj value ] -> [ obj obj key value ] -> [ obj ]
+        duk_dup(self.ptr, -2);
+        duk_swap_top(self.ptr, -2);
+        duk_push_lstring(self.ptr, name.as_ptr() as *const i8,
+                         name.len() as duk_size_t);
+        duk_swap_top(self.ptr, -2);
+        let status = duk_rust_pprop(self.ptr, DUK_RUST_PROP_PUT, 3);
+        self.pop_result(status).map(|_| ())
+    }
+
+    /// Register a table of Rust callbacks and numeric constants on the
+    /// object at the dotted `path`, creating it if necessary.  Each
+    /// function is given as `(name, callback, arg_count)`.
+    ///
+    /// Properties are stored one at a time, functions first, and we stop
+    /// at the first one which can't be stored, such as one with a setter
+    /// which throws.  Anything stored before that is left in place, as
+    /// are any objects created along `path`.  We don't use duktape's
+    /// `duk_put_function_list` and `duk_put_number_list`, because they
+    /// throw instead of returning errors, and can't attach the Rust
+    /// function pointer each of our callbacks needs.
+    ///
+    /// ```
+    /// use duktape::{Context, Value, DuktapeResult};
+    ///
+    /// fn twice(_ctx: &mut Context, args: &[Value<'static>]) ->
+    ///     DuktapeResult<Value<'static>>
+    /// {
+    ///     match &args[0] {
+    ///         &Value::Number(n) => Ok(Value::Number(2.0 * n)),
+    ///         _ => Ok(Value::Undefined)
+    ///     }
+    /// }
+    ///
+    /// let mut ctx = Context::new().unwrap();
+    /// ctx.register_module("app.math", &[("twice", twice, Some(1))],
+    ///                     &[("ANSWER", 42.0)]).unwrap();
+    /// assert_eq!(Value::Number(84.0),
+    ///            ctx.eval("app.math.twice(app.math.ANSWER)").unwrap());
+    /// ```
+    pub fn register_module(&mut self, path: &str,
+                           functions: &[(&str, Callback, Option<u16>)],
+                           numbers: &[(&str, f64)]) -> DuktapeResult<()>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                self.run(|ctx| {
+                    try!(ctx.push_namespace(path));
+                    let result = ctx.put_module(functions, numbers);
+                    duk_pop(ctx.ptr);
+                    result
+                })
+            })
+        }
+    }
+
+    /// Store `functions` and `numbers` in the object on top of the stack.
+    unsafe fn put_module(&mut self,
+                         functions: &[(&str, Callback, Option<u16>)],
+                         numbers: &[(&str, f64)]) -> DuktapeResult<()>
+    {
+        for &(name, f, arg_count) in functions.iter() {
+            self.push_callback(f, arg_count);
+            try!(self.put_prop(name));
+        }
+        for &(name, n) in numbers.iter() {
+            duk_push_number(self.ptr, n);
+            try!(self.put_prop(name));
+        }
+        Ok(())
+    }
+
+    /// Register a family of functions in the object at the dotted `path`,
+    /// all of which are handled by `dispatcher`.  Each function is given as
+    /// `(name, magic, arg_count)`, and `dispatcher` is passed the magic
+    /// number of the function being called.
+    ///
+    /// Unlike `register` and `register_module`, this stores nothing on the
+    /// individual function objects, which saves memory when registering
+    /// hundreds of functions.  Instead, each function's duktape magic
+    /// number indexes a table kept alongside the heap.  Functions with the
+    /// same dispatcher and magic number share an entry, and a heap may
+    /// hold at most 65535 entries.
+    ///
+    /// ```
+    /// use duktape::{Context, Value, DuktapeResult};
+    ///
+    /// fn math(_ctx: &mut Context, magic: i32, args: &[Value<'static>]) ->
+    ///     DuktapeResult<Value<'static>>
+    /// {
+    ///     match (magic, &args[0]) {
+    ///         (0, &Value::Number(n)) => Ok(Value::Number(n + 1.0)),
+    ///         (1, &Value::Number(n)) => Ok(Value::Number(n - 1.0)),
+    ///         _ => Ok(Value::Undefined)
+    ///     }
+    /// }
+    ///
+    /// let mut ctx = Context::new().unwrap();
+    /// ctx.register_dispatched("math", math, &[("inc", 0, Some(1)),
+    ///                                         ("dec", 1, Some(1))]).unwrap();
+    /// assert_eq!(Value::Number(2.0),
+    ///            ctx.eval("math.dec(math.inc(math.inc(1)))").unwrap());
+    /// ```
+    pub fn register_dispatched(&mut self, path: &str, dispatcher: Dispatcher,
+                               functions: &[(&str, i16, Option<u16>)]) ->
+        DuktapeResult<()>
+    {
+        // Make sure the heap's table has room for all our functions.
+        // Functions which were registered before reuse their entries.
+        match unsafe { heap_state(self.ptr) } {
+            Some(ref state) => {
+                let added: HashSet<i32> = functions.iter()
+                    .map(|&(_, magic, _)| magic as i32)
+                    .filter(|&magic| !state.dispatcher_indices.contains_key(
+                        &(dispatcher as usize, magic)))
+                    .collect();
+                if state.dispatchers.len() + added.len() > MAX_DISPATCHERS {
+                    return Err(DuktapeError::new(
+                        ErrorCode::Range, "Too many dispatched functions"));
+                }
+            }
+            None => {
+                return Err(DuktapeError::new(
+                    ErrorCode::Unsupported,
+                    "register_dispatched needs a heap created by Context"));
+            }
+        }
+        install_dispatch();
+
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                try!(self.run(|ctx| ctx.push_namespace(path)));
+                for &(name, magic, arg_count) in functions.iter() {
+                    let c_arg_count = arg_count.map(|n| n as duk_int_t)
+                        .unwrap_or(DUK_VARARGS);
+                    duk_push_c_function(self.ptr,
+                                        Some(duk_rust_dispatch_trampoline),
+                                        c_arg_count);
+                    // Only add a table entry once our function has been
+                    // stored.  Until then, a setter which gets hold of it
+                    // can't call anything.
+                    duk_set_magic(self.ptr, -1, NO_DISPATCHER);
+                    duk_dup_top(self.ptr);
+                    if let Err(err) = self.run(|ctx| ctx.put_prop(name)) {
+                        duk_pop_2(self.ptr);
+                        return Err(err);
+                    }
+                    let key = (dispatcher as usize, magic as i32);
+                    let idx = match heap_state(self.ptr) {
+                        Some(state) => match state.dispatcher_indices
+                            .get(&key).cloned()
+                        {
+                            Some(idx) => Some(idx),
+                            None if state.dispatchers.len() <
+                                MAX_DISPATCHERS =>
+                            {
+                                state.dispatchers.push((dispatcher,
+                                                        magic as i32));
+                                let idx = state.dispatchers.len() - 1;
+                                state.dispatcher_indices.insert(key, idx);
+                                Some(idx)
+                            }
+                            None => None
+                        },
+                        None => None
+                    };
+                    let idx = match idx {
+                        Some(idx) => idx,
+                        None => {
+                            duk_pop_2(self.ptr);
+                            return Err(DuktapeError::new(
+                                ErrorCode::Range,
+                                "Too many dispatched functions"));
+                        }
+                    };
+                    // duktape keeps the low 16 bits, and `dispatch_entry`
+                    // reads them back as unsigned.
+                    duk_set_magic(self.ptr, -1, idx as duk_int_t);
+                    duk_pop(self.ptr);
+                }
+                duk_pop(self.ptr);
+                Ok(())
+            })
+        }
+    }
+
+    /// Push the object at the dotted `path`, starting from the global
+    /// object, and creating empty objects for any missing parts.  An empty
+    /// path is the global object itself.  On error, the stack is left
+    /// unchanged.
+    unsafe fn push_namespace(&mut self, path: &str) -> DuktapeResult<()> {
+        duk_push_global_object(self.ptr);
+        if path.is_empty() { return Ok(()); }
+        for name in path.split('.') {
+            // [ parent ] -> [ parent child ]
+            duk_dup_top(self.ptr);
+            duk_push_lstring(self.ptr, name.as_ptr() as *const i8,
+                             name.len() as duk_size_t);
+            let status = duk_rust_pprop(self.ptr, DUK_RUST_PROP_GET, 2);
+            if status != DUK_EXEC_SUCCESS {
+                let err = self.pop_error();
+                duk_pop(self.ptr);
+                return Err(err);
+            }
+            if duk_is_undefined(self.ptr, -1) != 0 {
+                duk_pop(self.ptr);
+                duk_push_object(self.ptr);
+                duk_dup(self.ptr, -2);
+                duk_push_lstring(self.ptr, name.as_ptr() as *const i8,
+                                 name.len() as duk_size_t);
+                duk_dup(self.ptr, -3);
+                let status = duk_rust_pprop(self.ptr, DUK_RUST_PROP_PUT, 3);
+                if status != DUK_EXEC_SUCCESS {
+                    let err = self.pop_error();
+                    duk_pop_2(self.ptr);
+                    return Err(err);
+                }
+                duk_pop(self.ptr);
+            } else if duk_is_object(self.ptr, -1) == 0 {
+                duk_pop_2(self.ptr);
+                return Err(DuktapeError::new(
+                    ErrorCode::Type,
+                    &format!("{} in {} is not an object", name, path)));
+            }
+            duk_remove(self.ptr, -2); // Remove the parent.
+        }
+        Ok(())
+    }
+
+    /// Register a Rust closure as a global JavaScript function.  Unlike
+    /// `register`, the closure may capture state.  It is owned by the
+    /// JavaScript function object, and will be dropped when that function
+    /// is garbage collected or the context is destroyed.
+    pub fn register_closure<F>(&mut self, fn_name: &str, f: F,
+                               arg_count: Option<u16>)
+        where F: FnMut(&mut Context, &[Value<'static>]) ->
+                     DuktapeResult<Value<'static>> + 'static
+    {
+        let mut f = f;
+        self.register_boxed(fn_name, Box::new(move |ctx, arg_count| {
+            let args = try!(unsafe { get_args(ctx, arg_count) });
+            f(ctx, &args)
+        }), arg_count)
+    }
+
+    /// Register a Rust function or closure which takes typed arguments,
+    /// such as `|x: f64, name: String| -> DuktapeResult<bool>`.  Arguments
+    /// are decoded automatically, and calling the function with the wrong
+    /// number of arguments, or arguments which can't be decoded, throws a
+    /// `TypeError` naming the problem.  You may need to spell out the
+    /// closure's return type, as above.
+    pub fn register_typed<Args, F>(&mut self, fn_name: &str, f: F)
+        where F: TypedCallback<Args>
+    {
+        let callback = f.into_callback(fn_name.to_string());
+        self.register_boxed(fn_name, callback, None)
+    }
+
+    /// Get details of the callback which is currently running, from which
+    /// it can also get its `this` value and function object.  This may
+    /// only be called from inside a Rust callback, before the callback
+    /// returns.
+    pub fn call_info(&mut self) -> DuktapeResult<CallInfo> {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                // Outside of any call, this pushes `undefined`.
+                duk_push_current_function(self.ptr);
+                if duk_is_function(self.ptr, -1) == 0 {
+                    duk_pop(self.ptr);
+                    return Err(DuktapeError::from_str(
+                        "call_info called outside of a callback"));
+                }
+                let function_ptr = duk_get_heapptr(self.ptr, -1);
+                let is_dispatched = duk_get_c_function(self.ptr, -1)
+                    .map(|f| f as usize) ==
+                    Some(duk_rust_dispatch_trampoline as usize);
+                duk_pop(self.ptr);
+                let magic = match dispatch_entry(self.ptr) {
+                    Some((_, magic)) if is_dispatched => magic,
+                    _ => duk_get_current_magic(self.ptr)
+                };
+                Ok(CallInfo::new(duk_is_constructor_call(self.ptr) != 0,
+                                 duk_is_strict_call(self.ptr) != 0, magic,
+                                 function_ptr))
+            })
+        }
+    }
+
+    /// Register a Rust closure which reads its own arguments, as a global
+    /// JavaScript function.  The closure is passed the number of
+    /// arguments, which are left at stack indices `0..arg_count`, instead
+    /// of a slice of `Value`s.  Callbacks registered any other way get a
+    /// copy of every buffer argument, so use this to read or write buffers
+    /// in place with `get_buffer`.  Otherwise, this behaves like
+    /// `register_closure`.
+    pub fn register_raw<F>(&mut self, fn_name: &str, f: F,
+                           arg_count: Option<u16>)
+        where F: FnMut(&mut Context, usize) -> DuktapeResult<Value<'static>> +
+                     'static
+    {
+        self.register_boxed(fn_name, Box::new(f), arg_count)
+    }
+
+    /// Register an already-boxed closure as a global JavaScript function.
+    fn register_boxed(&mut self, fn_name: &str, f: RawCallback,
+                    arg_count: Option<u16>)
+    {
+        let c_arg_count =
+            arg_count.map(|n| n as duk_int_t).unwrap_or(DUK_VARARGS);
+        // Box our closure twice, so we can store it as a thin pointer.
+        let boxed: Box<RawCallback> = Box::new(f);
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                duk_push_global_object(self.ptr);
+                duk_push_rust_function(self.ptr,
+                                       Some(rust_duk_closure_callback),
+                                       c_arg_count);
+
+                // Hand ownership of our closure to the function object, and
+                // make sure it gets freed along with it.
+                attach_owned(self.ptr, -1, &CLOSURE,
+                             Box::into_raw(boxed) as *mut c_void);
+
+                // Store our function in a global property.  If that fails,
+                // the finalizer will still free our closure.
+                let result = self.run(|ctx| ctx.put_prop(fn_name));
+                duk_pop(self.ptr);
+                if let Err(err) = result {
+                    warn!("Could not register {}: {}", fn_name, err);
+                }
+            })
+        }
+    }
+}
+
+/// Create a heap which owns `state`, using `allocator`.  If `load_globals`
+/// is false, we replace the global object with an empty one.
+pub fn create_context(mut state: HeapState, allocator: Allocator,
+                      load_globals: bool) -> DuktapeResult<Context> {
+    install_exec_timeout_check();
+    state.tracking = allocator == Allocator::Tracking;
+    let state = Box::into_raw(Box::new(state));
+    register_heap(state);
+    let ptr = unsafe {
+        match allocator {
+            Allocator::Tracking =>
+                duk_create_heap(Some(rust_duk_alloc), Some(rust_duk_realloc),
+                                Some(rust_duk_free), state as *mut c_void,
+                                Some(rust_duk_fatal)),
+            Allocator::System =>
+                duk_create_heap(None, None, None, state as *mut c_void,
+                                Some(rust_duk_fatal))
+        }
+    };
+    if ptr.is_null() {
+        unregister_heap(state);
+        unsafe { drop(Box::from_raw(state)); }
+        return Err(DuktapeError::from_str("Could not create heap"));
+    }
+    unsafe { (*state).main_ctx = ptr; }
+    if !load_globals {
+        unsafe {
+            duk_push_object(ptr);
+            duk_set_global_object(ptr);
+        }
+    }
+    Ok(Context{ptr: ptr, owned: true})
+}
+
+impl Drop for Context {
+  fn drop(&mut self) {
+      if self.owned {
+          unsafe {
+              // Look up our state first, because we can't after this.
+              let state = heap_state(self.ptr).map(|s| s as *mut HeapState);
+              // Finalizers may drop persistent references, which must not
+              // touch a heap that is being destroyed.
+              if let Some(state) = state { (*state).alive.set(false); }
+              duk_destroy_heap(self.ptr);
+              if let Some(state) = state {
+                  // Free any Rust values whose objects had their finalizers
+                  // replaced by a script.
+                  for (p, free) in (*state).owned.drain() {
+                      free(p as *mut c_void);
+                  }
+                  unregister_heap(state);
+                  drop(Box::from_raw(state));
+              }
+          }
+      }
+  }
+}
+
+/// How deeply nested an array or object may be before `Context::get`
+/// gives up on converting it.
+pub const MAX_NESTING_DEPTH: usize = 64;
+
+/// The longest array that `Context::get` will convert.  Scripts can make
+/// an empty array claim to be billions of elements long.
+pub const MAX_ARRAY_LENGTH: usize = 1 << 20;
+
+/// A "internal" property key used for storing Rust function pointers, which
+/// can't be accessed from JavaScript without a lot of trickery.
+pub const RUST_FN_PROP: [i8; 5] = [-1, 'r' as i8, 'f' as i8, 'n' as i8, 0];
+
+/// The standard `name` property of error objects.
+const NAME_PROP: [i8; 5] = ['n' as i8, 'a' as i8, 'm' as i8, 'e' as i8, 0];
+
+/// Our generic callback function.
+unsafe extern "C" fn rust_duk_callback(ctx: *mut duk_context) -> duk_ret_t {
+    // Here, we create a mutable Context pointing into an existing duktape
+    // heap.  But this is theoretically safe, because the only way to
+    // invoke JavaScript code is to use a mutable context while calling
+    // into C.  So this is really an indirect mutable borrow.
+    assert!(ctx != null_mut());
+    let mut ctx = Context::from_borrowed_mut_ptr(ctx);
+    //println!("In callback: {}", ctx.dump_context());
+
+    // Recover our Rust function pointer.
+    let f: Callback = assert_stack_height_unchanged!(ctx, {
+        duk_push_current_function(ctx.ptr);
+        duk_get_prop_string(ctx.ptr, -1, RUST_FN_PROP.as_ptr());
+        let p = duk_get_pointer(ctx.ptr, -1);
+        duk_pop_n(ctx.ptr, 2);
+        assert!(p != null_mut());
+        transmute(p)
+    });
+
+    invoke_callback(&mut ctx, |ctx, args| f(ctx, args))
+}
+
+/// The most functions `register_dispatched` can create on one heap, since
+/// duktape magic numbers are 16 bits, and we keep one value back for
+/// `NO_DISPATCHER`.
+const MAX_DISPATCHERS: usize = (1 << 16) - 1;
+
+/// The magic number of a dispatched function without a table entry.
+const NO_DISPATCHER: duk_int_t = (1 << 16) - 1;
+
+/// Point `duk_rust_dispatch_trampoline` at `rust_duk_dispatch`.  This is
+/// global, so we only need to do it once.
+fn install_dispatch() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        unsafe { duk_rust_set_dispatch(Some(rust_duk_dispatch)); }
+    });
+}
+
+/// Look up the dispatcher and magic number for the function being called,
+/// which must have been created by `register_dispatched`.
+unsafe fn dispatch_entry(ctx: *mut duk_context) -> Option<(Dispatcher, i32)> {
+    let idx = duk_get_current_magic(ctx) as u16 as usize;
+    heap_state(ctx).and_then(|state| state.dispatchers.get(idx).cloned())
+}
+
+/// Our callback function for functions registered with
+/// `register_dispatched`, called by `duk_rust_dispatch_trampoline`.
+unsafe extern "C" fn rust_duk_dispatch(ctx: *mut duk_context) -> duk_ret_t {
+    assert!(ctx != null_mut());
+    let mut ctx = Context::from_borrowed_mut_ptr(ctx);
+    match dispatch_entry(ctx.ptr) {
+        Some((f, magic)) =>
+            invoke_callback(&mut ctx, |ctx, args| f(ctx, magic, args)),
+        None => throw_error(&mut ctx, ErrorCode::Type,
+                            "Unknown dispatched function")
+    }
+}
+
+/// Our callback function for boxed closures.
+unsafe extern "C" fn rust_duk_closure_callback(ctx: *mut duk_context) ->
+    duk_ret_t
+{
+    assert!(ctx != null_mut());
+    let mut ctx = Context::from_borrowed_mut_ptr(ctx);
+
+    // Take our closure away from the function object while it runs.  If
+    // the closure calls back into JavaScript, which calls the closure
+    // again, we'd otherwise end up with two mutable references to it.
+    duk_push_current_function(ctx.ptr);
+    let owned = get_owned(ctx.ptr, -1, &CLOSURE);
+    if let Some(p) = owned {
+        if !p.is_null() { set_owned(ctx.ptr, -1, &CLOSURE, null_mut()); }
+    }
+    duk_pop(ctx.ptr);
+    let p = match owned {
+        Some(p) if !p.is_null() => p as *mut RawCallback,
+        Some(_) => return throw_error(&mut ctx, ErrorCode::Error,
+                                      "Rust closure is already running"),
+        None => return throw_error(&mut ctx, ErrorCode::Error,
+                                   "Rust closure has been freed")
+    };
+
+    let ret = invoke_raw(&mut ctx, |ctx, arg_count| (*p)(ctx, arg_count));
+
+    // Put our closure back.  We're still running, so our function object
+    // can't have been finalized in the meantime.
+    duk_push_current_function(ctx.ptr);
+    set_owned(ctx.ptr, -1, &CLOSURE, p as *mut c_void);
+    duk_pop(ctx.ptr);
+    ret
+}
+
+/// The boxed closures owned by functions created by `register_closure`.
+static CLOSURE: OwnedKind = OwnedKind{
+    name: "closure",
+    finalizer: Some(rust_duk_closure_finalizer),
+    free: free_closure
+};
+
+/// Finalizer for functions created by `register_closure`, which frees the
+/// boxed closure when the function is garbage collected or the heap is
+/// destroyed.
+unsafe extern "C" fn rust_duk_closure_finalizer(ctx: *mut duk_context) ->
+    duk_ret_t
+{
+    // The object being finalized is our only argument.
+    finalize_owned(ctx, 0, &CLOSURE);
+    0
+}
+
+/// Drop a boxed closure.
+unsafe fn free_closure(p: *mut c_void) {
+    abort_on_panic!("unexpected panic while dropping a closure", {
+        drop(Box::from_raw(p as *mut RawCallback));
+    });
+}
+
+/// A kind of Rust value which JavaScript objects can own, such as the
+/// closure behind a function created by `register_closure`.
+pub struct OwnedKind {
+    /// Keeps the heap stash keys of different kinds apart, so that one
+    /// kind of value is never mistaken for another.
+    pub name: &'static str,
+    /// The finalizer which frees the value along with its object.
+    pub finalizer: duk_c_function,
+    /// Drop a value of this kind.
+    pub free: unsafe fn(*mut c_void)
+}
+
+/// Push the heap stash key for the Rust value of `kind` owned by the object
+/// at `idx`.  We keep these values in the stash, keyed by the object's heap
+/// pointer, rather than on the object itself, where scripts could freeze
+/// them.
+unsafe fn push_owned_key(ctx: *mut duk_context, idx: duk_idx_t,
+                         kind: &OwnedKind) {
+    let key = format!("rust-owned:{}:{:x}", kind.name,
+                      duk_get_heapptr(ctx, idx) as usize);
+    duk_push_lstring(ctx, key.as_ptr() as *const i8, key.len() as duk_size_t);
+}
+
+/// Does the object at `idx` still have the finalizer for `kind`?  Scripts
+/// can replace it using `Duktape.fin`, after which nothing removes the
+/// object's stash entry, and a new object may later reuse its address.
+unsafe fn has_finalizer(ctx: *mut duk_context, idx: duk_idx_t,
+                        kind: &OwnedKind) -> bool {
+    duk_get_finalizer(ctx, idx);
+    let finalizer = duk_get_c_function(ctx, -1);
+    duk_pop(ctx);
+    finalizer.map(|f| f as usize) == kind.finalizer.map(|f| f as usize)
+}
+
+/// Read the stash entry for the object at `idx`, without checking it.
+unsafe fn read_owned(ctx: *mut duk_context, idx: duk_idx_t,
+                     kind: &OwnedKind) -> Option<*mut c_void> {
+    duk_push_heap_stash(ctx);
+    push_owned_key(ctx, idx, kind);
+    duk_get_prop(ctx, -2);
+    let p = if duk_is_pointer(ctx, -1) != 0 {
+        Some(duk_get_pointer(ctx, -1))
+    } else {
+        None
+    };
+    duk_pop_2(ctx);
+    p
+}
+
+/// Write the stash entry for the object at `idx`.
+unsafe fn write_owned(ctx: *mut duk_context, idx: duk_idx_t,
+                      kind: &OwnedKind, p: *mut c_void) {
+    duk_push_heap_stash(ctx);
+    push_owned_key(ctx, idx, kind);
+    duk_push_pointer(ctx, p);
+    duk_put_prop(ctx, -3);
+    duk_pop(ctx);
+}
+
+/// Free a value of `kind` which no object owns any more.  Null pointers,
+/// which mark values in use, are ignored.
+unsafe fn free_owned(ctx: *mut duk_context, kind: &OwnedKind,
+                     p: *mut c_void) {
+    if p.is_null() { return; }
+    if let Some(state) = heap_state(ctx) {
+        state.owned.remove(&(p as usize));
+    }
+    (kind.free)(p);
+}
+
+/// Give the newly created object at `idx` ownership of `p`, and install
+/// the finalizer for `kind` to free it along with the object.  Any entry
+/// already stored under the object's address was left behind by a dead
+/// object whose finalizer was replaced, so we free it.
+pub unsafe fn attach_owned(ctx: *mut duk_context, idx: duk_idx_t,
+                           kind: &OwnedKind, p: *mut c_void) {
+    let idx = duk_normalize_index(ctx, idx);
+    if let Some(stale) = take_owned(ctx, idx, kind) {
+        free_owned(ctx, kind, stale);
+    }
+    write_owned(ctx, idx, kind, p);
+    if let Some(state) = heap_state(ctx) {
+        state.owned.insert(p as usize, kind.free);
+    }
+    duk_push_c_function(ctx, kind.finalizer, 1);
+    duk_set_finalizer(ctx, idx);
+}
+
+/// Get the Rust value of `kind` owned by the object at `idx`.  This is
+/// `None` if it doesn't own one, and a null pointer while the value is in
+/// use.  If the object's finalizer has been replaced, its entry may belong
+/// to a dead object at the same address, so we free it instead.
+pub unsafe fn get_owned(ctx: *mut duk_context, idx: duk_idx_t,
+                        kind: &OwnedKind) -> Option<*mut c_void> {
+    let idx = duk_normalize_index(ctx, idx);
+    if has_finalizer(ctx, idx, kind) {
+        return read_owned(ctx, idx, kind);
+    }
+    if let Some(p) = take_owned(ctx, idx, kind) {
+        free_owned(ctx, kind, p);
+    }
+    None
+}
+
+/// Set the Rust value of `kind` owned by the object at `idx`, which must
+/// have come from `get_owned`.  A null pointer marks the value as being in
+/// use.  If the object's finalizer was replaced while the value was in use,
+/// nothing would free it along with the object, so we free it now.
+pub unsafe fn set_owned(ctx: *mut duk_context, idx: duk_idx_t,
+                        kind: &OwnedKind, p: *mut c_void) {
+    let idx = duk_normalize_index(ctx, idx);
+    if has_finalizer(ctx, idx, kind) {
+        write_owned(ctx, idx, kind, p);
+    } else {
+        if let Some(old) = take_owned(ctx, idx, kind) {
+            free_owned(ctx, kind, old);
+        }
+        free_owned(ctx, kind, p);
+    }
+}
+
+/// Remove the Rust value of `kind` owned by the object at `idx`, returning
+/// it.
+unsafe fn take_owned(ctx: *mut duk_context, idx: duk_idx_t,
+                     kind: &OwnedKind) -> Option<*mut c_void> {
+    let idx = duk_normalize_index(ctx, idx);
+    let p = read_owned(ctx, idx, kind);
+    if p.is_some() {
+        duk_push_heap_stash(ctx);
+        push_owned_key(ctx, idx, kind);
+        duk_del_prop(ctx, -2);
+        duk_pop(ctx);
+    }
+    p
+}
+
+/// Free the Rust value of `kind` owned by the object at `idx`.  This is
+/// the body of each kind's finalizer.  We forget the value before freeing
+/// it, in case the object is resurrected and used or finalized again.
+pub unsafe fn finalize_owned(ctx: *mut duk_context, idx: duk_idx_t,
+                             kind: &OwnedKind) {
+    if let Some(p) = take_owned(ctx, idx, kind) {
+        free_owned(ctx, kind, p);
+    }
+}
+
+/// Push an error object and return `DUK_RET_RUST_THROW`, so that
+/// `duk_rust_trampoline` will throw it once we've returned.
+pub unsafe fn throw_error(ctx: &mut Context, code: ErrorCode, msg: &str) ->
+    duk_ret_t
+{
+    // C strings can't contain NUL, so drop any we find.
+    let encoded = to_cesu8(msg);
+    let bytes: Vec<u8> = encoded.iter().cloned().filter(|&b| b != 0).collect();
+    let c_msg = CString::new(bytes).unwrap();
+    duk_push_error_object_string(ctx.ptr, code as duk_errcode_t, null_mut(), 0,
+                                 c_msg.as_ptr());
+    DUK_RET_RUST_THROW
+}
+
+/// Extract a printable message from a panic payload.
+fn panic_message(payload: &Box<Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&'static str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "Box<Any>".to_string()
+    }
+}
+
+/// Convert the first `arg_count` values on the stack, which are the
+/// arguments of the current call, to Rust values.
+unsafe fn get_args(ctx: &mut Context, arg_count: usize) ->
+    DuktapeResult<Vec<Value<'static>>>
+{
+    let mut args = Vec::with_capacity(arg_count);
+    for i in 0..arg_count {
+        match ctx.get(i as duk_idx_t) {
+            Ok(arg) => args.push(arg),
+            Err(err) => {
+                let msg = format!("argument {}: {}", i,
+                                  err.message().unwrap_or("cannot convert"));
+                return Err(DuktapeError::new(ErrorCode::Type, &msg));
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Convert the arguments on the stack to Rust values, pass them to `f`,
+/// and translate its result into something duktape understands.
+pub unsafe fn invoke_callback<F>(ctx: &mut Context, f: F) -> duk_ret_t
+    where F: FnOnce(&mut Context, &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+{
+    invoke_raw(ctx, |ctx, arg_count| {
+        let args = try!(get_args(ctx, arg_count));
+        f(ctx, &args)
+    })
+}
+
+/// Like `invoke_callback`, but leave the arguments on the stack, and only
+/// tell `f` how many there are.
+pub unsafe fn invoke_raw<F>(ctx: &mut Context, f: F) -> duk_ret_t
+    where F: FnOnce(&mut Context, usize) -> DuktapeResult<Value<'static>>
+{
+    // ERROR-HANDLING NOTE: Try to avoid any Rust panics or duktape unwinds
+    // inside this function.  They sort-of work--at least well enough to
+    // debug this crate--but they probably corrupt at least one of the two
+    // heaps.
+    let arg_count = duk_get_top(ctx.ptr) as usize;
+
+    // Call our function.
+    let catch_panics =
+        heap_state(ctx.ptr).map(|state| state.catch_panics).unwrap_or(false);
+    let result = if catch_panics {
+        let top = duk_get_top(ctx.ptr);
+        match panic::catch_unwind(AssertUnwindSafe(|| f(ctx, arg_count))) {
+            Ok(result) => result,
+            Err(payload) => {
+                // Throw away anything the callback left on the stack, and
+                // throw an InternalError into the calling script.
+                duk_set_top(ctx.ptr, top);
+                let msg = panic_message(&payload);
+                if let Some(state) = heap_state(ctx.ptr) {
+                    if state.pending_panic.is_none() {
+                        state.pending_panic = Some(msg.clone());
+                    }
+                }
+                let ret = throw_error(ctx, ErrorCode::Internal,
+                                      &format!("panic in Rust callback: {}",
+                                               msg));
+                // Internal errors are ordinary `Error` objects in duktape,
+                // so label this one ourselves.
+                let name = CString::new("InternalError").unwrap();
+                duk_push_string(ctx.ptr, name.as_ptr());
+                duk_put_prop_string(ctx.ptr, -2, NAME_PROP.as_ptr());
+                return ret;
+            }
+        }
+    } else {
+        abort_on_panic!("unexpected panic in code called from JavaScript", {
+            f(ctx, arg_count)
+        })
+    };
+
+    // Return our result.
+    match result {
+        // No return value.
+        Ok(Value::Undefined) => { 0 }
+        // A single return value.
+        Ok(ref val) => {
+            match ctx.push_old(val) {
+                Ok(()) => 1,
+                Err(err) => {
+                    let msg = err.message().unwrap_or("Cannot return value")
+                        .to_string();
+                    throw_error(ctx, ErrorCode::Range, &msg)
+                }
+            }
+        }
+        Err(ref err) => {
+            let code = err_code(err) as duk_int_t;
+            match err_message(err) {
+                // An error with an actual error message.  We can't call
+                // duk_throw from here, because that would perform a
+                // non-local exit from a Rust function, which is a Bad
+                // Idea.  So we push the error object and let
+                // duk_rust_trampoline throw it after we've returned.
+                &Some(ref msg) => throw_error(ctx, err_code(err), msg),
+                // A generic error using one of the standard codes.
+                &None => { -code }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_eval() {
+    let mut ctx = Context::new().unwrap();
+    assert_eq!(Value::Undefined, ctx.eval("undefined").unwrap());
+    assert_eq!(Value::Null, ctx.eval("null").unwrap());
+    assert_eq!(Value::Bool(true), ctx.eval("true").unwrap());
+    assert_eq!(Value::Bool(false), ctx.eval("false").unwrap());
+    assert_eq!(Value::Number(5.0), ctx.eval("2 + 3").unwrap());
+
+    assert_eq!(Value::String(Cow::Borrowed("é")), ctx.eval("'é'").unwrap());
+}
+
+#[test]
+fn test_eval_arrays_and_objects() {
+    let mut ctx = Context::new().unwrap();
+    assert_eq!(Value::Array(vec!()), ctx.eval("[]").unwrap());
+    assert_eq!(Value::Object(vec!()), ctx.eval("({})").unwrap());
+    assert_eq!(Value::Array(vec!(Value::Number(1.0),
+                                 Value::String(Cow::Borrowed("a")),
+                                 Value::Null)),
+               ctx.eval("[1, 'a', null]").unwrap());
+
+    // Keys come back in insertion order, and values may be nested.
+    let expected = Value::Object(vec!(
+        ("b".to_string(), Value::Bool(true)),
+        ("a".to_string(), Value::Array(vec!(Value::Number(2.0))))));
+    assert_eq!(expected, ctx.eval("({b: true, a: [2]})").unwrap());
+
+    // Structured values survive a round trip through a JavaScript function.
+    ctx.eval("function id(x) { return x; }").unwrap();
+    ctx.eval("var nested = {list: [1, {x: 'y'}]};").unwrap();
+    assert_eq!(Value::Object(vec!(
+        ("list".to_string(), Value::Array(vec!(
+            Value::Number(1.0),
+            Value::Object(vec!(
+                ("x".to_string(), Value::String(Cow::Borrowed("y")))))))))),
+               ctx.eval("id(nested)").unwrap());
+
+    // Cyclic values are reported as errors instead of looping forever.
+    assert!(ctx.eval("var c = {}; c.self = c; c").is_err());
+
+    // Proxy traps and getters run while we read a value, and anything they
+    // throw comes back as an error instead of unwinding through Rust.
+    let err = ctx.eval("var no = function () { throw new RangeError('trap'); }; \
+                        new Proxy({a: 1}, {enumerate: no, ownKeys: no})")
+        .unwrap_err();
+    assert_eq!(ErrorCode::Range, err.code());
+    assert_eq!(Some("trap"), err.message());
+    let err = ctx.eval("new Proxy({a: 1}, {get: no})").unwrap_err();
+    assert_eq!(ErrorCode::Range, err.code());
+    let err = ctx.eval("[1, {get x() { throw new RangeError('getter'); }}]")
+        .unwrap_err();
+    assert_eq!(Some("getter"), err.message());
+    assert_eq!(Value::Number(1.0), ctx.eval("1").unwrap());
+}
+
+#[test]
+fn test_deeply_nested_values() {
+    fn nest(n: usize) -> Value<'static> {
+        let mut v = Value::Object(vec!(("x".to_string(),
+                                        Value::Number(1.0))));
+        for _ in 0..n { v = Value::Array(vec!(v)); }
+        v
+    }
+
+    fn depth(_ctx: &mut Context, args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        let mut n = 0;
+        let mut v = &args[0];
+        while let &Value::Array(ref elems) = v { v = &elems[0]; n += 1; }
+        Ok(Value::Number(n as f64))
+    }
+
+    fn deep(_ctx: &mut Context, args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        match args[0] {
+            Value::Number(n) => Ok(nest(n as usize)),
+            _ => Ok(Value::Undefined)
+        }
+    }
+
+    let mut ctx = Context::new().unwrap();
+    ctx.register("depth", depth, Some(1));
+    ctx.register("deep", deep, Some(1));
+    ctx.eval("function nest(n) { \
+                  var v = {x: 1}; \
+                  for (var i = 0; i < n; i++) v = [v]; \
+                  return v; \
+              }").unwrap();
+
+    // Callbacks only get a small stack, but can still pass deep values
+    // back and forth.
+    assert_eq!(Value::Number(60.0), ctx.eval("depth(nest(60))").unwrap());
+    assert_eq!(nest(60), ctx.eval("deep(60)").unwrap());
+
+    let err = ctx.eval("nest(100)").unwrap_err();
+    assert_eq!(Some("Value is nested too deeply"), err.message());
+    let err = ctx.eval("var c = {}; c.c = c; c").unwrap_err();
+    assert_eq!(Some("Value is nested too deeply"), err.message());
+    let err = ctx.eval("deep(100)").unwrap_err();
+    assert_eq!(ErrorCode::Range, err.code());
+    assert_eq!(Some("Value is nested too deeply"), err.message());
+
+    // Scripts control an array's length, so huge ones are refused instead
+    // of being allocated.
+    let err = ctx.eval("var a = []; a.length = 4294967295; a").unwrap_err();
+    assert_eq!(Some("Array is too long to convert"), err.message());
+    let err = ctx.eval("depth(a)").unwrap_err();
+    assert_eq!(ErrorCode::Type, err.code());
+    assert_eq!(Some("argument 0: Array is too long to convert"),
+               err.message());
+}
+
+#[test]
+fn test_unicode_supplementary_planes() {
+    // Pay careful attention to characters U+10000 and greater, because
+    // duktape uses CESU-8 internally, which isn't _quite_ valid UTF-8.
+    // This is thanks to the fact that JavaScript uses 16-bit characters
+    // and allows manipulating invalid UTF-16 data with mismatched
+    // surrogate pairs.
+    let mut ctx = Context::new().unwrap();
+
+    assert_eq!(Value::String(Cow::Borrowed("𓀀")), ctx.eval("'𓀀'").unwrap());
+    assert_eq!(Value::String(Cow::Borrowed("𓀀")),
+               ctx.eval("'\\uD80C\\uDC00'").unwrap());
+
+    ctx.eval("function id(x) { return x; }").unwrap();
+    assert_eq!(Ok(Value::String(Cow::Borrowed("𓀀"))),
+               ctx.call("id", &[&"𓀀"]));
+}
+
+#[test]
+fn test_eval_as_and_call_as() {
+    #[derive(RustcDecodable, PartialEq, Debug)]
+    struct Point { x: f64, y: f64 }
+
+    let mut ctx = Context::new().unwrap();
+    assert_eq!(Ok(5.0f64), ctx.eval_as::<f64>("2 + 3"));
+    assert_eq!(Ok(vec!("a".to_string(), "b".to_string())),
+               ctx.eval_as::<Vec<String>>("['a', 'b']"));
+    assert_eq!(Ok(Point{x: 1.0, y: 2.0}),
+               ctx.eval_as::<Point>("({x: 1, y: 2})"));
+
+    ctx.eval("function point(x, y) { return {x: x, y: [y]}; }").unwrap();
+    let err = ctx.call_as::<Point>("point", &[&1.0f64, &2.0f64]).unwrap_err();
+    assert_eq!("Expected number at .y", format!("{}", err));
+
+    let err = ctx.eval_as::<Vec<Vec<f64>>>("[[1], [2, 'x']]").unwrap_err();
+    assert_eq!("Expected number at [1][1]", format!("{}", err));
+
+    // Script errors are reported as usual.
+    assert!(ctx.eval_as::<f64>("3 +").is_err());
+}
+
+#[test]
+fn test_timeouts() {
+    let mut ctx = Context::new().unwrap();
+
+    ctx.set_deadline(Duration::from_millis(50));
+    let err = ctx.eval("while (true) {}").unwrap_err();
+    assert!(err.is_timeout());
+    assert_eq!(ErrorCode::Range, err.code());
+
+    // Catching the RangeError doesn't let a script keep running.
+    let err = ctx.eval("while (true) { try { while (true) {} } catch (e) {} }")
+        .unwrap_err();
+    assert!(err.is_timeout());
+
+    // Each new call gets a fresh deadline.
+    assert_eq!(Value::Number(2.0), ctx.eval("1 + 1").unwrap());
+
+    ctx.clear_limits();
+    ctx.set_instruction_budget(1000);
+    assert!(ctx.eval("for (;;) {}").unwrap_err().is_timeout());
+    assert!(!ctx.eval("3 +").unwrap_err().is_timeout());
+}
+
+#[test]
+fn test_memory_limit() {
+    // Too small to even create a heap.
+    assert!(Context::with_memory_limit(16).is_err());
+
+    let mut ctx = Context::with_memory_limit(1024 * 1024).unwrap();
+    let err = ctx.eval("(function () { \
+                            var a = []; \
+                            while (true) { a.push('xxxxxxxxxx' + a.length); } \
+                        })()").unwrap_err();
+    assert_eq!(ErrorCode::Alloc, err.code());
+    // Our block headers count towards the limit, too.
+    assert!(ctx.memory_stats().unwrap().peak_bytes <= 1024 * 1024);
+
+    // Once the garbage is collected, we can keep going.
+    assert_eq!(Value::Number(2.0), ctx.eval("1 + 1").unwrap());
+
+    // Errors thrown after catching an allocation failure are reported as
+    // they are.
+    let err = ctx.eval("(function () { \
+                            var a = []; \
+                            try { \
+                                while (true) { a.push('xx' + a.length); } \
+                            } catch (e) { a = null; } \
+                            null.foo; \
+                        })()").unwrap_err();
+    assert_eq!(ErrorCode::Type, err.code());
+}
+
+#[test]
+fn test_memory_stats() {
+    let mut ctx = Context::new().unwrap();
+    let before = ctx.memory_stats().unwrap();
+    assert!(before.bytes_in_use > 0);
+    assert!(before.live_blocks > 0);
+    assert!(before.allocations >= before.live_blocks as u64);
+    assert!(before.live_objects > 0);
+    assert!(before.live_blocks >= before.live_objects);
+
+    ctx.eval("var big = []; \
+              for (var i = 0; i < 10000; i++) { big.push('item ' + i); }")
+        .unwrap();
+    let grown = ctx.memory_stats().unwrap();
+    assert!(grown.bytes_in_use > before.bytes_in_use + 100000);
+    assert!(grown.live_blocks > before.live_blocks);
+    assert!(grown.peak_bytes >= grown.bytes_in_use);
+
+    // Strings aren't objects, but these are.
+    ctx.eval("var objects = []; \
+              for (var i = 0; i < 1000; i++) { objects.push({i: i}); }")
+        .unwrap();
+    let counted = ctx.memory_stats().unwrap();
+    assert!(counted.live_objects >= grown.live_objects + 1000);
+    ctx.eval("objects = null;").unwrap();
+    ctx.gc();
+    assert!(ctx.memory_stats().unwrap().live_objects + 1000 <=
+            counted.live_objects);
+
+    ctx.eval("big = null;").unwrap();
+    ctx.gc();
+    let collected = ctx.memory_stats().unwrap();
+    assert!(collected.bytes_in_use < grown.bytes_in_use);
+    assert!(collected.peak_bytes >= grown.peak_bytes);
+}
+
+#[test]
+fn test_eval_errors() {
+    let mut ctx = Context::new().unwrap();
+    assert_eq!(true, ctx.eval("3 +").is_err());
+    assert_eq!(ErrorCode::Syntax, ctx.eval("3 +").unwrap_err().code());
+
+    let err = ctx.eval_from("test.js", "\n\nnull.foo").unwrap_err();
+    assert_eq!(ErrorCode::Type, err.code());
+    assert_eq!(Some("TypeError"), err.name());
+    assert!(err.message().is_some());
+    assert_eq!(Some("test.js"), err.file_name());
+    assert_eq!(Some(3), err.line_number());
+    assert!(err.value().is_none());
+
+    let err = ctx.eval("throw new RangeError('out of range')").unwrap_err();
+    assert_eq!(ErrorCode::Range, err.code());
+    assert_eq!(Some("out of range"), err.message());
+    assert_eq!("RangeError: out of range", format!("{}", err));
+
+    // Values which aren't errors are kept as they are, even if they look
+    // like errors.
+    let err = ctx.eval("throw 42").unwrap_err();
+    assert_eq!(ErrorCode::Error, err.code());
+    assert_eq!(Some(Value::Number(42.0)), err.value());
+    assert_eq!(Some("42"), err.message());
+    assert_eq!(None, err.name());
+    let err = ctx.eval("throw {name: 'x', message: 'y'}").unwrap_err();
+    assert_eq!(None, err.name());
+    assert_eq!(Some(Value::Object(vec!(
+        ("name".to_string(), Value::String(Cow::Borrowed("x"))),
+        ("message".to_string(), Value::String(Cow::Borrowed("y")))))),
+               err.value());
+    assert_eq!(Some(Value::Undefined),
+               ctx.eval("throw undefined").unwrap_err().value());
+    assert_eq!(Some(Value::Null),
+               ctx.eval("throw null").unwrap_err().value());
+    assert_eq!(Some(Value::Buffer(Cow::Borrowed(b"ab"))),
+               ctx.eval("throw Duktape.Buffer('ab')").unwrap_err().value());
+    let p = 0x1234 as *mut c_void;
+    ctx.register_closure("pointer", move |_, _| Ok(Value::Pointer(p)),
+                         Some(0));
+    let err = ctx.eval("throw pointer()").unwrap_err();
+    assert_eq!(Some(Value::Pointer(p)), err.value());
+
+    // Errors can be sent to other threads and compared, even if they hold
+    // a pointer or a number.
+    fn assert_send_sync_eq<T: Send + Sync + Eq>(_: &T) {}
+    assert_send_sync_eq(&err);
+
+    // Errors with throwing getters don't take down the process.
+    let err = ctx.eval("var e = new TypeError('bad'); \
+                        Object.defineProperty(e, 'lineNumber', \
+                            {get: function () { throw 1; }}); \
+                        throw e;").unwrap_err();
+    assert_eq!(ErrorCode::Type, err.code());
+    assert_eq!(None, err.line_number());
+
+    // So do results with throwing getters.
+    let err = ctx.eval("({a: 1, get b() { throw new RangeError('no'); }})")
+        .unwrap_err();
+    assert_eq!(ErrorCode::Range, err.code());
+    let err = ctx.eval("var a = [1]; Object.defineProperty(a, 0, \
+                            {get: function () { throw new Error('x'); }}); \
+                        a").unwrap_err();
+    assert_eq!(Some("x"), err.message());
+}
+
+#[test]
+fn test_call_values_paths_and_methods() {
+    let mut ctx = Context::new().unwrap();
+    ctx.eval("var app = {handlers: {prefix: 'got ', \
+                                    onMessage: function (m) { \
+                                        return this.prefix + m; }}}; \
+              function makeAdder(n) { \
+                  return function (x) { return x + n; }; } \
+              function Point(x, y) { this.x = x; this.y = y; }").unwrap();
+
+    assert_eq!(Value::String(Cow::Borrowed("got hi")),
+               ctx.call_path("app.handlers.onMessage", &[&"hi"]).unwrap());
+    assert_eq!(Value::Number(3.0), ctx.call_path("Math.max", &[&3.0f64])
+               .unwrap());
+    let err = ctx.call_path("app.missing.onMessage", &[]).unwrap_err();
+    assert_eq!(ErrorCode::Type, err.code());
+    assert_eq!(Some("app.missing is null or undefined"), err.message());
+
+    let add10 = ctx.eval_to_ref("makeAdder(10)").unwrap();
+    assert_eq!(Value::Number(15.0), ctx.call_ref(&add10, &[&5.0f64]).unwrap());
+
+    let handlers = ctx.eval_to_ref("app.handlers").unwrap();
+    assert_eq!(Value::String(Cow::Borrowed("got x")),
+               ctx.call_method(&handlers, "onMessage", &[&"x"]).unwrap());
+    assert!(ctx.call_method(&handlers, "missing", &[]).is_err());
+
+    assert_eq!(Value::Object(vec!(("x".to_string(), Value::Number(1.0)),
+                                  ("y".to_string(), Value::Number(2.0)))),
+               ctx.construct("Point", &[&1.0f64, &2.0f64]).unwrap());
+    let err = ctx.construct("Math.max", &[]).unwrap_err();
+    assert_eq!(ErrorCode::Type, err.code());
+
+    // Getters along the path may throw.
+    ctx.eval("var trap = {get f() { throw new RangeError('no'); }}; \
+              Object.defineProperty(this, 'gone', \
+                  {get: function () { throw new Error('gone'); }})")
+        .unwrap();
+    let err = ctx.call_path("trap.f.g", &[]).unwrap_err();
+    assert_eq!(ErrorCode::Range, err.code());
+    assert_eq!(Some("no"), err.message());
+    assert_eq!(Some("gone"), ctx.call("gone", &[]).unwrap_err().message());
+    assert_eq!(Some("no"),
+               ctx.construct("trap.f", &[]).unwrap_err().message());
+    assert_eq!(Value::Number(2.0), ctx.eval("1 + 1").unwrap());
+}
+
+#[test]
+fn test_compiled_scripts() {
+    let mut ctx = Context::new().unwrap();
+    let script = ctx.compile("counter.js", "var counter; \
+                                            counter = (counter || 0) + 1; \
+                                            counter * 2").unwrap();
+    assert_eq!(Value::Number(2.0), ctx.run_script(&script).unwrap());
+    assert_eq!(Value::Number(4.0), ctx.run_script(&script).unwrap());
+    assert_eq!(Value::Number(6.0), ctx.run_script(&script).unwrap());
+
+    let add = ctx.compile_function("add.js", "function (a, b) { \
+                                                  return a + b; }").unwrap();
+    assert_eq!(Value::Number(3.0),
+               ctx.call_script(&add, &[&1.0f64, &2.0f64]).unwrap());
+    assert_eq!(Value::Number(7.0),
+               ctx.call_script(&add, &[&3.0f64, &4.0f64]).unwrap());
+
+    let err = ctx.compile("bad.js", "1 +").err().unwrap();
+    assert_eq!(ErrorCode::Syntax, err.code());
+    let thrower = ctx.compile("throw.js", "throw new RangeError('no')")
+        .unwrap();
+    assert_eq!(ErrorCode::Range, ctx.run_script(&thrower).unwrap_err().code());
+
+    // Scripts see the same `this` as `eval`, even in strict mode.
+    let this = ctx.compile("this.js", "'use strict'; \
+                                       this === Function('return this')()")
+        .unwrap();
+    assert_eq!(Value::Bool(true), ctx.run_script(&this).unwrap());
+    assert_eq!(Value::Bool(true), ctx.eval("this.counter === 3").unwrap());
+}
+
+#[test]
+fn test_globals() {
+    #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+    struct Settings { name: String, limits: Vec<u32> }
+
+    let mut ctx = Context::new().unwrap();
+    let tricky = "'); throw new Error('injected'); ('";
+    ctx.set_global("message", &tricky).unwrap();
+    assert_eq!(Value::Number(tricky.len() as f64),
+               ctx.eval("message.length").unwrap());
+
+    let settings = Settings{name: "demo".to_string(), limits: vec!(1, 2)};
+    ctx.set_global("settings", &settings).unwrap();
+    assert_eq!(Value::Number(3.0),
+               ctx.eval("settings.limits[0] + settings.limits[1]").unwrap());
+    ctx.eval("settings.limits.push(3)").unwrap();
+    let updated: Settings = ctx.get_global("settings").unwrap();
+    assert_eq!(vec!(1, 2, 3), updated.limits);
+    assert!(ctx.get_global::<String>("settings").is_err());
+
+    assert!(ctx.has_global("settings").unwrap());
+    assert!(ctx.has_global("Math").unwrap());
+    assert!(!ctx.has_global("missing").unwrap());
+    assert!(ctx.delete_global("settings").unwrap());
+    assert!(!ctx.has_global("settings").unwrap());
+    ctx.eval("var declared = 1;").unwrap();
+    assert!(!ctx.delete_global("declared").unwrap());
+}
+
+#[test]
+fn test_call_info() {
+    fn describe(ctx: &mut Context, _args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        let info = try!(ctx.call_info());
+        let this = try!(info.this(ctx));
+        let this = try!(ctx.get_ref(&this));
+        let name = match this {
+            Value::Undefined => "undefined".to_string(),
+            Value::Object(ref props) => format!("object/{}", props.len()),
+            _ => "other".to_string()
+        };
+        Ok(Value::String(Cow::Owned(format!("{} {} {}", name,
+                                             info.is_strict_call,
+                                             info.magic))))
+    }
+
+    fn construct(ctx: &mut Context, args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        let info = try!(ctx.call_info());
+        if !info.is_constructor_call {
+            return Err(DuktapeError::new(ErrorCode::Type,
+                                         "Point must be called with new"));
+        }
+        let x = match args[0] { Value::Number(x) => x, _ => 0.0 };
+        let this = try!(info.this(ctx));
+        let point = try!(ObjectRef::from_ref(ctx, this));
+        try!(point.set(ctx, "x", &x));
+        Ok(Value::Undefined)
+    }
+
+    fn tag(ctx: &mut Context, _args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        let info = try!(ctx.call_info());
+        let function = try!(info.function(ctx));
+        let function = try!(ObjectRef::from_ref(ctx, function));
+        function.get_value(ctx, "tag")
+    }
+
+    // A CallInfo can't be used from another callback.
+    fn outer(ctx: &mut Context, _args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        let info = try!(ctx.call_info());
+        ctx.set_user_data(Some(info));
+        ctx.eval("inner()")
+    }
+
+    fn inner(ctx: &mut Context, _args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        let info = ctx.user_data::<Option<CallInfo>>()
+            .and_then(|slot| slot.take());
+        match info {
+            Some(info) => info.this(ctx).map(|_| Value::Null),
+            None => Ok(Value::Undefined)
+        }
+    }
+
+    let mut ctx = Context::new().unwrap();
+    ctx.register("describe", describe, Some(0));
+    ctx.register("Point", construct, Some(1));
+    ctx.register("tag", tag, Some(0));
+    assert_eq!(Value::String(Cow::Borrowed("undefined true 0")),
+               ctx.eval("describe()").unwrap());
+    assert_eq!(Value::String(Cow::Borrowed("object/1 true 0")),
+               ctx.eval("describe.call({a: 1})").unwrap());
+    assert_eq!(Value::Number(3.0),
+               ctx.eval("var p = new Point(3); p instanceof Point && p.x")
+                   .unwrap());
+    assert_eq!(ErrorCode::Type, ctx.eval("Point(3)").unwrap_err().code());
+    assert_eq!(Value::String(Cow::Borrowed("hi")),
+               ctx.eval("tag.tag = 'hi'; tag()").unwrap());
+    ctx.register("outer", outer, Some(0));
+    ctx.register("inner", inner, Some(0));
+    assert_eq!(Some("CallInfo used outside of its callback"),
+               ctx.eval("outer()").unwrap_err().message());
+
+    assert!(ctx.call_info().is_err());
+}
+
+#[test]
+fn test_dispatched_functions() {
+    fn api(ctx: &mut Context, magic: i32, args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        let info = try!(ctx.call_info());
+        assert_eq!(magic, info.magic);
+        match magic {
+            -1 => Err(DuktapeError::new(ErrorCode::Range, "negative")),
+            _ => Ok(Value::Number((magic * 10) as f64 + args.len() as f64))
+        }
+    }
+
+    let mut ctx = Context::new().unwrap();
+    let names: Vec<String> = (0..300).map(|i| format!("f{}", i)).collect();
+    let mut functions: Vec<(&str, i16, Option<u16>)> = names.iter()
+        .enumerate().map(|(i, name)| (&name[..], i as i16, None)).collect();
+    functions.push(("fails", -1, Some(0)));
+    ctx.register_dispatched("host.api", api, &functions).unwrap();
+
+    assert_eq!(Value::Number(0.0), ctx.eval("host.api.f0()").unwrap());
+    assert_eq!(Value::Number(2992.0),
+               ctx.eval("host.api.f299(1, 2)").unwrap());
+    assert_eq!(Value::Number(71.0),
+               ctx.eval("host.api.f7.call(null, 'x')").unwrap());
+    assert_eq!(Value::Bool(true),
+               ctx.eval("host.api.f1 instanceof Function && \
+                         Object.getPrototypeOf(host.api.f1) === \
+                         Object.getPrototypeOf(host.api.f2)").unwrap());
+    assert_eq!(ErrorCode::Range,
+               ctx.eval("host.api.fails()").unwrap_err().code());
+
+    // Scripts can't redirect a function by changing its prototype.
+    assert_eq!(Value::Number(10.0),
+               ctx.eval("Object.setPrototypeOf(host.api.f1, {}); \
+                         Function.prototype.call.call(host.api.f1)")
+                   .unwrap());
+    assert_eq!(Value::Number(20.0),
+               ctx.eval("Object.setPrototypeOf(host.api.f2, host.api.f3); \
+                         host.api.f2()").unwrap());
+
+    // Different dispatchers get their own entries.
+    fn other(_ctx: &mut Context, magic: i32, _args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        Ok(Value::Number(-magic as f64))
+    }
+    ctx.register_dispatched("host.other", other, &[("g", 5, None)]).unwrap();
+    assert_eq!(Value::Number(-5.0), ctx.eval("host.other.g()").unwrap());
+    assert_eq!(Value::Number(50.0), ctx.eval("host.api.f5()").unwrap());
+    ctx.eval("var n = 1;").unwrap();
+    assert!(ctx.register_dispatched("n", api, &[("f", 0, None)]).is_err());
+
+    // Failed registrations don't use up table entries, and functions
+    // which escape from them can't be called.
+    ctx.eval("var trap = {set f(fn) { escaped = fn; throw 1; }}, escaped;")
+        .unwrap();
+    let before = unsafe { heap_state(ctx.ptr).unwrap().dispatchers.len() };
+    for _ in 0..10 {
+        assert!(ctx.register_dispatched("trap", api, &[("f", 1, None)])
+                .is_err());
+    }
+    assert_eq!(before,
+               unsafe { heap_state(ctx.ptr).unwrap().dispatchers.len() });
+    assert_eq!(ErrorCode::Type, ctx.eval("escaped()").unwrap_err().code());
+
+    // Registering the same functions again reuses their entries, so the
+    // table never fills up.
+    for _ in 0..(MAX_DISPATCHERS + 10) {
+        ctx.register_dispatched("host.again", api, &[("f", 5, None)])
+            .unwrap();
+    }
+    ctx.register_dispatched("host.again", api, &functions).unwrap();
+    assert_eq!(before,
+               unsafe { heap_state(ctx.ptr).unwrap().dispatchers.len() });
+    assert_eq!(Value::Number(50.0), ctx.eval("host.again.f5()").unwrap());
+    assert_eq!(Value::Number(2992.0),
+               ctx.eval("host.again.f299(1, 2)").unwrap());
 }
 
 #[test]
@@ -361,8 +2540,7 @@ fn test_call_function_by_name() {
     assert_eq!(Ok(Value::Bool(true)),  ctx.call("id", &[&true]));
     assert_eq!(Ok(Value::Bool(false)), ctx.call("id", &[&false]));
     assert_eq!(Ok(Value::Number(1.5)), ctx.call("id", &[&1.5f64]));
-    // FIXME: un comment out the tests
-    // assert_eq!(Ok(Value::String(Cow::Borrowed("é"))),
-               // ctx.call("id", &[&"é"]));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("é"))),
+               ctx.call("id", &[&"é"]));
 }
 
@@ -0,0 +1,461 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::mem::zeroed;
+use std::process;
+use std::ptr::null_mut;
+use std::rc::Rc;
+use std::sync::Once;
+use std::time::{Duration, Instant};
+use libc::{c_char, c_void};
+
+use duktape_sys::*;
+use errors::base::*;
+use contexts::callback::{Dispatcher, FatalHandler};
+
+/// Roughly how many bytecode instructions duktape executes between calls
+/// to our execution timeout check.  This matches duktape's default
+/// interrupt counter interval.
+pub const INSTRUCTIONS_PER_CHECK: u64 = 256 * 1024;
+
+/// Memory usage statistics for a duktape heap.  Everything except
+/// `live_objects` is seen by our allocator, which doesn't know which
+/// allocations belong to which JavaScript objects, so it counts raw memory
+/// blocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// How many bytes are currently allocated.
+    pub bytes_in_use: usize,
+    /// The largest value `bytes_in_use` has reached.
+    pub peak_bytes: usize,
+    /// How many allocations have been made over the life of the heap,
+    /// counting each successful `realloc` as a new one.
+    pub allocations: u64,
+    /// How many blocks are currently allocated.  This includes strings,
+    /// buffers, property tables and other internal data as well as
+    /// objects, and one object may own several blocks.
+    pub live_blocks: usize,
+    /// How many JavaScript objects (including functions and threads) the
+    /// heap holds.  Unreachable objects are included until the garbage
+    /// collector frees them; call `Context::gc` first to leave them out.
+    pub live_objects: usize
+}
+
+/// Rust-side state shared by every `Context` pointing at the same duktape
+/// heap.  We pass this to `duk_create_heap` as the allocator's `udata`, so
+/// that it can be found again from any context on the heap, including the
+/// borrowed contexts we create inside callbacks.
+pub struct HeapState {
+    /// Should panics in Rust callbacks be turned into JavaScript
+    /// exceptions, instead of aborting the process?
+    pub catch_panics: bool,
+
+    /// The message of a panic caught in a callback, which will be reported
+    /// by the outermost `eval` or `call` when it returns.
+    pub pending_panic: Option<String>,
+
+    /// How many calls to `eval` or `call` are currently running on this
+    /// heap.
+    pub depth: usize,
+
+    /// How long each outermost `eval` or `call` may run.
+    pub timeout: Option<Duration>,
+
+    /// Roughly how many bytecode instructions each outermost `eval` or
+    /// `call` may execute.
+    pub instruction_budget: Option<u64>,
+
+    /// When the currently running script must stop.
+    deadline: Option<Instant>,
+
+    /// How many more timeout checks the currently running script may
+    /// survive before it exceeds its instruction budget.
+    checks_left: Option<u64>,
+
+    /// Has the currently running script been interrupted?
+    timed_out: bool,
+
+    /// The maximum number of bytes the heap may allocate.
+    pub memory_limit: Option<usize>,
+
+    /// Does the heap use our tracking allocator?
+    pub tracking: bool,
+
+    /// Memory usage statistics maintained by our allocator.
+    pub stats: MemoryStats,
+
+    /// Has an allocation been refused since the outermost call started?
+    alloc_failed: bool,
+
+    /// Called before we abort on a fatal duktape error.
+    pub fatal_handler: Option<FatalHandler>,
+
+    /// Should `eval` compile code in strict mode?
+    pub strict: bool,
+
+    /// Arbitrary data stored by the application.
+    pub user_data: Option<Box<Any>>,
+
+    /// The dispatcher and magic number of each function registered with
+    /// `Context::register_dispatched`, indexed by the duktape magic number
+    /// of the function object.
+    pub dispatchers: Vec<(Dispatcher, i32)>,
+
+    /// The index in `dispatchers` of each dispatcher (as a `usize`) and
+    /// magic number, so that registering the same function again reuses
+    /// its entry.
+    pub dispatcher_indices: HashMap<(usize, i32), usize>,
+
+    /// Every Rust value owned by a JavaScript object (see
+    /// `context::attach_owned`), and the function which frees it.  Scripts
+    /// can replace the finalizers which would normally free these, so we
+    /// free whatever is left when the heap is destroyed.
+    pub owned: HashMap<usize, unsafe fn(*mut c_void)>,
+
+    /// The context returned by `duk_create_heap`.
+    pub main_ctx: *mut duk_context,
+
+    /// Set to false just before the heap is destroyed, so that any
+    /// `PersistentRef` which outlives it knows not to touch it.
+    pub alive: Rc<Cell<bool>>,
+
+    /// The heap stash index to use for the next `PersistentRef`.
+    next_ref: duk_uarridx_t
+}
+
+impl HeapState {
+    /// Create the state for a new heap.
+    pub fn new() -> HeapState {
+        HeapState{catch_panics: false, pending_panic: None, depth: 0,
+                  timeout: None, instruction_budget: None, deadline: None,
+                  checks_left: None, timed_out: false, memory_limit: None,
+                  tracking: false, stats: MemoryStats::default(),
+                  alloc_failed: false,
+                  fatal_handler: None, strict: false, user_data: None,
+                  dispatchers: vec!(), dispatcher_indices: HashMap::new(),
+                  owned: HashMap::new(), main_ctx: null_mut(),
+                  alive: Rc::new(Cell::new(true)), next_ref: 0}
+    }
+
+    /// Called when we start running JavaScript code.  Entering the
+    /// outermost call starts the clock on any timeout.
+    pub fn enter(&mut self) {
+        if self.depth == 0 {
+            self.deadline = self.timeout.map(|t| Instant::now() + t);
+            self.checks_left = self.instruction_budget.map(|n| {
+                (n + INSTRUCTIONS_PER_CHECK - 1) / INSTRUCTIONS_PER_CHECK
+            });
+            self.timed_out = false;
+            self.alloc_failed = false;
+        }
+        self.depth += 1;
+    }
+
+    /// Called when we finish running JavaScript code, with the error it
+    /// threw, if any.  When the outermost call finishes, this returns any
+    /// error which should override its result.
+    pub fn exit(&mut self, error: Option<&DuktapeError>) ->
+        Option<DuktapeError>
+    {
+        self.depth -= 1;
+        if self.depth > 0 { return None; }
+
+        self.deadline = None;
+        self.checks_left = None;
+        let timed_out = self.timed_out;
+        self.timed_out = false;
+        // Duktape retries failed allocations after collecting garbage, so
+        // a refused allocation only matters if the script failed because
+        // of it, and not because of something it did after catching it.
+        let alloc_failed =
+            self.alloc_failed && error.map_or(false, is_alloc_error);
+        self.alloc_failed = false;
+        if let Some(msg) = self.pending_panic.take() {
+            Some(err_from_panic(&msg))
+        } else if timed_out {
+            Some(err_from_timeout())
+        } else if alloc_failed {
+            Some(err_from_alloc(self.memory_limit.unwrap_or(0)))
+        } else {
+            None
+        }
+    }
+
+    /// Record that an allocation of `old_size` bytes is being replaced by
+    /// one of `new_size` bytes.  Returns false, and changes nothing, if
+    /// this would grow the heap past our memory limit.  Block counts and the
+    /// peak are updated separately by `allocated` and `freed`, once we know
+    /// whether the system allocator succeeded.
+    pub fn reserve(&mut self, old_size: usize, new_size: usize) -> bool {
+        let in_use = self.stats.bytes_in_use - old_size + new_size;
+        if new_size > old_size {
+            if let Some(limit) = self.memory_limit {
+                if in_use > limit {
+                    self.alloc_failed = true;
+                    return false;
+                }
+            }
+        }
+        self.stats.bytes_in_use = in_use;
+        true
+    }
+
+    /// Record that an allocation of `size` bytes has been freed.
+    pub fn release(&mut self, size: usize) {
+        self.stats.bytes_in_use -= size;
+    }
+
+    /// Record a successful allocation.  `new_block` is false for a
+    /// `realloc` of an existing block.  Only memory we actually got counts
+    /// towards the peak, not reservations for allocations which failed.
+    pub fn allocated(&mut self, new_block: bool) {
+        self.stats.allocations += 1;
+        if new_block { self.stats.live_blocks += 1; }
+        if self.stats.bytes_in_use > self.stats.peak_bytes {
+            self.stats.peak_bytes = self.stats.bytes_in_use;
+        }
+    }
+
+    /// Record that a block has been freed.
+    pub fn freed(&mut self) {
+        self.stats.live_blocks -= 1;
+    }
+
+    /// Pick a heap stash index for a new `PersistentRef`.  Wrapping around
+    /// would take billions of references, so we don't worry about it.
+    pub fn next_ref_key(&mut self) -> duk_uarridx_t {
+        let key = self.next_ref;
+        self.next_ref = self.next_ref.wrapping_add(1);
+        key
+    }
+
+    /// Should the running script be interrupted?  Once this returns true,
+    /// it keeps doing so until the outermost call returns, so that scripts
+    /// can't keep running by catching the error.
+    fn check_timeout(&mut self) -> bool {
+        if self.timed_out { return true; }
+        if let Some(n) = self.checks_left {
+            // Each check means another `INSTRUCTIONS_PER_CHECK` or so have
+            // run, so the budget is used up by the check which takes us to
+            // zero.
+            let n = n.saturating_sub(1);
+            self.checks_left = Some(n);
+            if n == 0 { self.timed_out = true; }
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline { self.timed_out = true; }
+        }
+        self.timed_out
+    }
+}
+
+/// Is `err` the error duktape throws when an allocation fails?  Duktape
+/// has no `AllocError` class, so it throws a plain `Error`, or a
+/// preallocated "double error" if it can't even allocate that.
+fn is_alloc_error(err: &DuktapeError) -> bool {
+    err.code() == ErrorCode::Alloc ||
+        (err.name() == Some("Error") &&
+         (err.message() == Some("alloc failed") ||
+          err.message() == Some("error in error handling")))
+}
+
+thread_local!(
+    /// The `HeapState` of every heap created by this library on this
+    /// thread.  Heaps created elsewhere may have allocator `udata` which
+    /// points at something else entirely, so we only trust pointers we
+    /// find here.
+    static HEAPS: RefCell<HashSet<usize>> = RefCell::new(HashSet::new())
+);
+
+/// Record that `state` belongs to a heap created by this library.
+pub fn register_heap(state: *mut HeapState) {
+    HEAPS.with(|heaps| { heaps.borrow_mut().insert(state as usize); });
+}
+
+/// Record that the heap which owned `state` has been destroyed.
+pub fn unregister_heap(state: *mut HeapState) {
+    let _ = HEAPS.try_with(|heaps| {
+        heaps.borrow_mut().remove(&(state as usize));
+    });
+}
+
+/// Was `udata` registered with `register_heap`?
+fn is_heap_state(udata: *mut c_void) -> bool {
+    !udata.is_null() && HEAPS.try_with(|heaps| {
+        heaps.borrow().contains(&(udata as usize))
+    }).unwrap_or(false)
+}
+
+/// Look up the `HeapState` associated with `ctx`.  Returns `None` if the
+/// heap wasn't created by this library.
+pub unsafe fn heap_state<'a>(ctx: *mut duk_context) ->
+    Option<&'a mut HeapState>
+{
+    let mut funcs: duk_memory_functions = zeroed();
+    duk_get_memory_functions(ctx, &mut funcs);
+    if is_heap_state(funcs.udata) {
+        (funcs.udata as *mut HeapState).as_mut()
+    } else {
+        None
+    }
+}
+
+/// Make sure duktape can find our execution timeout check.
+pub fn install_exec_timeout_check() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        unsafe { duk_rust_set_exec_timeout_check(Some(exec_timeout_check)); }
+    });
+}
+
+/// Called by duktape when it can't recover from an error, for example when
+/// an error is thrown with no `duk_pcall` to catch it.  Duktape must not
+/// continue after this, so we log the error, give the application a chance
+/// to record it, and abort.
+pub unsafe extern "C" fn rust_duk_fatal(ctx: *mut duk_context,
+                                        code: duk_errcode_t,
+                                        msg: *const c_char) {
+    let msg = if msg.is_null() {
+        "(no message)".to_string()
+    } else {
+        CStr::from_ptr(msg).to_string_lossy().into_owned()
+    };
+    error!("duktape fatal error {}: {}", code, msg);
+
+    let state = if ctx.is_null() { None } else { heap_state(ctx) };
+    match state.and_then(|state| state.fatal_handler.as_mut()) {
+        Some(handler) => {
+            // We're about to abort anyway, but a panic must not unwind
+            // into duktape.
+            abort_on_panic!("fatal error handler panicked", {
+                handler(code as i32, &msg);
+            });
+        }
+        None => {
+            let _ = writeln!(io::stderr(), "duktape fatal error {}: {}",
+                             code, msg);
+        }
+    }
+    process::abort();
+}
+
+/// Called periodically by duktape with the allocator `udata` of the heap
+/// running a script.
+unsafe extern "C" fn exec_timeout_check(udata: *mut c_void) -> duk_bool_t {
+    if !is_heap_state(udata) { return 0; }
+    match (udata as *mut HeapState).as_mut() {
+        Some(state) => if state.check_timeout() { 1 } else { 0 },
+        None => 0
+    }
+}
+
+#[test]
+fn test_foreign_heaps() {
+    use contexts::context::Context;
+    use types::Value;
+
+    // A heap we didn't create, whose udata isn't a `HeapState`.
+    let mut udata = [0xffu8; 4];
+    unsafe {
+        let ptr = duk_create_heap(None, None, None,
+                                  udata.as_mut_ptr() as *mut c_void, None);
+        {
+            let mut ctx = Context::from_borrowed_mut_ptr(ptr);
+            assert!(heap_state(ptr).is_none());
+            assert_eq!(None, ctx.memory_stats());
+            ctx.set_catch_panics(true);
+            assert_eq!(Value::Number(3.0), ctx.eval("1 + 2").unwrap());
+            assert!(ctx.eval_to_ref("({})").is_err());
+        }
+        duk_destroy_heap(ptr);
+    }
+    assert_eq!([0xffu8; 4], udata);
+}
+
+#[test]
+fn test_instruction_budget_boundary() {
+    // A budget of exactly two intervals runs out at the second check.
+    let mut state = HeapState::new();
+    state.instruction_budget = Some(2 * INSTRUCTIONS_PER_CHECK);
+    state.enter();
+    assert!(!state.check_timeout());
+    assert!(state.check_timeout());
+    assert!(state.exit(None).unwrap().is_timeout());
+
+    // One more instruction rounds up to a third interval.
+    state.instruction_budget = Some(2 * INSTRUCTIONS_PER_CHECK + 1);
+    state.enter();
+    assert!(!state.check_timeout());
+    assert!(!state.check_timeout());
+    assert!(state.check_timeout());
+    assert!(state.exit(None).unwrap().is_timeout());
+
+    // Nested calls share the budget of the outermost one.
+    state.instruction_budget = Some(INSTRUCTIONS_PER_CHECK);
+    state.enter();
+    state.enter();
+    assert!(state.check_timeout());
+    assert!(state.exit(None).is_none());
+    assert!(state.exit(None).unwrap().is_timeout());
+}
+
+#[test]
+fn test_peak_ignores_failed_allocations() {
+    let mut state = HeapState::new();
+    assert!(state.reserve(0, 100));
+    state.allocated(true);
+    assert_eq!(100, state.stats.peak_bytes);
+
+    // The system allocator refused a block, so we give the memory back.
+    assert!(state.reserve(0, 1000));
+    state.release(1000);
+    assert!(state.reserve(100, 5000));
+    assert!(state.reserve(5000, 100));
+    assert_eq!(100, state.stats.bytes_in_use);
+    assert_eq!(100, state.stats.peak_bytes);
+    assert_eq!(1, state.stats.live_blocks);
+}
+
+#[test]
+fn test_fatal_handler() {
+    use std::env;
+    use std::process::Command;
+    use contexts::context::Context;
+
+    // Fatal errors abort the process, so we run this test again in a child
+    // process, which is the one that actually fails.
+    if env::var_os("DUKTAPE_RS_FATAL_CHILD").is_some() {
+        struct StderrLogger;
+        impl ::log::Log for StderrLogger {
+            fn enabled(&self, _: &::log::Metadata) -> bool { true }
+            fn log(&self, record: &::log::Record) {
+                let _ = writeln!(io::stderr(), "logged: {}", record.args());
+            }
+            fn flush(&self) {}
+        }
+        static LOGGER: StderrLogger = StderrLogger;
+        ::log::set_logger(&LOGGER).unwrap();
+        ::log::set_max_level(::log::LevelFilter::Error);
+
+        let mut ctx = Context::with_fatal_handler(|code, msg| {
+            let _ = writeln!(io::stderr(), "handler: {} {}", code, msg);
+        }).unwrap();
+        let msg = ::std::ffi::CString::new("no way back").unwrap();
+        unsafe { duk_fatal(ctx.as_mut_ptr(), 7, msg.as_ptr()); }
+        unreachable!();
+    }
+
+    let output = Command::new(env::current_exe().unwrap())
+        .args(&["--exact", "contexts::heap::test_fatal_handler",
+                "--nocapture"])
+        .env("DUKTAPE_RS_FATAL_CHILD", "1")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("logged: duktape fatal error 7: no way back"),
+            "{}", stderr);
+    assert!(stderr.contains("handler: 7 no way back"), "{}", stderr);
+}
@@ -16,6 +16,16 @@ use duktape_sys::*;
 
 pub mod context;
 pub mod callback;
+pub mod buffer;
+pub mod heap;
+pub mod alloc;
+pub mod builder;
+pub mod persistent;
+pub mod script;
+pub mod bytecode;
+pub mod object;
+pub mod class;
+pub mod typed;
 
 use Context;
 use Callback;
@@ -24,8 +34,7 @@ use Callback;
 pub unsafe fn from_lstring(data: *const i8, len: duk_size_t) ->
     DuktapeResult<String>
 {
-    let ptr = data as u8;
-    let bytes = from_raw_parts(&ptr, len as usize) ;
+    let bytes = from_raw_parts(data as *const u8, len as usize);
     match from_cesu8(bytes) {
         Ok(str) => Ok(str.into_owned()),
         Err(_) => Err(DuktapeError::from_str("can't convert string to UTF-8"))
@@ -63,11 +72,22 @@ mod test {
         }
     }
 
+    pub fn rust_fill(ctx: &mut context::Context, _args: &[Value<'static>]) ->
+        DuktapeResult<Value<'static>>
+    {
+        let mut buf = try!(ctx.get_buffer(0));
+        for b in buf.iter_mut() { *b = 7; }
+        Ok(Value::Undefined)
+    }
+
     rust_callback!{rust_return_undefined, Ok(Value::Undefined)}
     rust_callback!{rust_return_simple_error,
                    Err(DuktapeError::from_code(ErrorCode::Type))}
     rust_callback!{rust_return_custom_error,
                    Err(DuktapeError::from_str("custom error"))}
+    rust_callback!{rust_return_range_error,
+                   Err(DuktapeError::new(ErrorCode::Range, "too big"))}
+    rust_callback!{rust_panic, panic!("boom")}
 }
 
 #[test]
@@ -92,4 +112,205 @@ fn test_callbacks() {
     ctx.register("custom_error", test::rust_return_custom_error, Some(0));
     let res = ctx.eval("custom_error()");
     assert!(res.is_err());
+    assert!(format!("{}", res.unwrap_err()).contains("custom error"));
+
+    // Error messages and classes are visible to JavaScript code.
+    assert_eq!(Value::String(Cow::Borrowed("custom error")),
+               ctx.eval("try { custom_error(); } catch (e) { e.message }")
+                   .unwrap());
+    ctx.register("range_error", test::rust_return_range_error, Some(0));
+    assert_eq!(Value::Bool(true),
+               ctx.eval("try { range_error(); } catch (e) { \
+                             e instanceof RangeError && e.message == 'too big' \
+                         }").unwrap());
+}
+
+#[test]
+fn test_namespaced_callbacks() {
+    let mut ctx = context::Context::new().unwrap();
+
+    // Intermediate objects are created as needed, and existing ones are
+    // reused.
+    ctx.eval("var app = {version: 2};").unwrap();
+    ctx.register_at("app.math.add", test::rust_add, Some(2)).unwrap();
+    ctx.register_at("app.math.sum", test::rust_add, None).unwrap();
+    ctx.register_at("add", test::rust_add, Some(2)).unwrap();
+    assert_eq!(Value::Number(5.0), ctx.eval("app.math.add(2, 3)").unwrap());
+    assert_eq!(Value::Number(6.0), ctx.eval("app.math.sum(1, 2, 3)").unwrap());
+    assert_eq!(Value::Number(2.0), ctx.eval("app.version").unwrap());
+    assert_eq!(Value::Number(3.0), ctx.eval("add(1, 2)").unwrap());
+    assert!(ctx.register_at("app.version.add", test::rust_add, None)
+            .is_err());
+
+    // A whole module at once.
+    ctx.register_module("app.util",
+                        &[("add", test::rust_add, Some(2)),
+                          ("nothing", test::rust_return_undefined, Some(0)),
+                          ("fail", test::rust_return_range_error, Some(0))],
+                        &[("ANSWER", 42.0), ("HALF", 0.5)]).unwrap();
+    assert_eq!(Value::Number(42.5),
+               ctx.eval("app.util.add(app.util.ANSWER, app.util.HALF)")
+                   .unwrap());
+    assert_eq!(Value::Undefined, ctx.eval("app.util.nothing()").unwrap());
+    assert_eq!(Value::Bool(true),
+               ctx.eval("try { app.util.fail(); } catch (e) { \
+                             e instanceof RangeError }").unwrap());
+    assert_eq!(Value::Bool(true),
+               ctx.eval("typeof app.math.add === 'function'").unwrap());
+
+    // Scripts may freeze namespaces or give them setters which throw.
+    ctx.eval("var locked = Object.freeze({}); \
+              var trap = {set f(v) { throw new RangeError('no'); }}; \
+              Object.defineProperty(this, 'fixed', {value: 1});").unwrap();
+    assert!(ctx.register_at("locked.add", test::rust_add, None).is_err());
+    assert!(ctx.register_module("locked", &[], &[("N", 1.0)]).is_err());
+    let err = ctx.register_at("trap.f", test::rust_add, None).unwrap_err();
+    assert_eq!(ErrorCode::Range, err.code());
+    let err = ctx.register_module("trap", &[("add", test::rust_add, None),
+                                            ("f", test::rust_add, None),
+                                            ("g", test::rust_add, None)],
+                                  &[]).unwrap_err();
+    assert_eq!(ErrorCode::Range, err.code());
+    assert_eq!(Value::Bool(true),
+               ctx.eval("typeof trap.add === 'function' && \
+                         !('g' in trap)").unwrap());
+    ctx.register("fixed", test::rust_add, None);
+    assert_eq!(Value::Number(1.0), ctx.eval("fixed").unwrap());
+}
+
+#[test]
+fn test_buffer_callbacks() {
+    use std::borrow::Cow;
+
+    let mut ctx = context::Context::new().unwrap();
+
+    // Buffers are copied in and out of JavaScript as `Value::Buffer`.
+    assert_eq!(Value::Buffer(Cow::Borrowed(&[1u8, 2, 255][..])),
+               ctx.eval("Duktape.dec('hex', '0102ff')").unwrap());
+
+    // A callback can modify a buffer argument in place.
+    ctx.register("fill", test::rust_fill, Some(1));
+    assert_eq!(Value::String(Cow::Borrowed("0707")),
+               ctx.eval("var b = Duktape.dec('hex', '0000'); fill(b); \
+                         Duktape.enc('hex', b)").unwrap());
+
+    // ...but only if it actually is a buffer.
+    assert!(ctx.eval("fill('not a buffer')").is_err());
+
+    // Raw callbacks can do the same without the buffer being copied into
+    // a `Value` first.
+    ctx.register_raw("sum", |ctx, arg_count| {
+        assert_eq!(1, arg_count);
+        let buf = try!(ctx.get_buffer(0));
+        Ok(Value::Number(buf.iter().map(|&b| b as f64).sum()))
+    }, Some(1));
+    assert_eq!(Value::Number(258.0),
+               ctx.eval("sum(Duktape.dec('hex', '0102ff'))").unwrap());
+    assert!(ctx.eval("sum('not a buffer')").is_err());
+}
+
+#[test]
+fn test_closure_callbacks() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Records when it's dropped, so we can check that closures get freed.
+    struct DropFlag(Rc<Cell<bool>>);
+    impl Drop for DropFlag {
+        fn drop(&mut self) { self.0.set(true); }
+    }
+
+    let dropped = Rc::new(Cell::new(false));
+    {
+        let mut ctx = context::Context::new().unwrap();
+
+        // A closure with mutable captured state.
+        let flag = DropFlag(dropped.clone());
+        let mut count = 0.0;
+        ctx.register_closure("next", move |_ctx, _args| {
+            let _ = &flag;
+            count += 1.0;
+            Ok(Value::Number(count))
+        }, Some(0));
+        assert_eq!(Value::Number(1.0), ctx.eval("next()").unwrap());
+        assert_eq!(Value::Number(2.0), ctx.eval("next()").unwrap());
+
+        // Freezing the function object doesn't get in our way.
+        assert_eq!(Value::Number(3.0),
+                   ctx.eval("Object.freeze(next); next()").unwrap());
+
+        // A closure which calls itself recursively is refused, rather
+        // than aliasing its own state.
+        ctx.register_closure("recurse", |ctx, _args| {
+            ctx.eval("recurse()")
+        }, Some(0));
+        let err = ctx.eval("recurse()").unwrap_err();
+        assert_eq!(ErrorCode::Error, err.code());
+        assert_eq!(Some("Rust closure is already running"), err.message());
+        assert!(!dropped.get());
+    }
+    // Destroying the heap runs our finalizer.
+    assert!(dropped.get());
+}
+
+#[test]
+fn test_closures_with_replaced_finalizers() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct DropFlag(Rc<Cell<bool>>);
+    impl Drop for DropFlag {
+        fn drop(&mut self) { self.0.set(true); }
+    }
+
+    let called_dropped = Rc::new(Cell::new(false));
+    let leaked_dropped = Rc::new(Cell::new(false));
+    {
+        let mut ctx = context::Context::new().unwrap();
+
+        // A function whose finalizer is replaced gives up its closure.
+        let flag = DropFlag(called_dropped.clone());
+        ctx.register_closure("called", move |_ctx, _args| {
+            let _ = &flag;
+            Ok(Value::Null)
+        }, Some(0));
+        assert_eq!(Value::Null, ctx.eval("called()").unwrap());
+        let err = ctx.eval("Duktape.fin(called, function() {}); called()")
+            .unwrap_err();
+        assert_eq!(Some("Rust closure has been freed"), err.message());
+        assert!(called_dropped.get());
+
+        // If the function is collected first, nothing can free its closure
+        // until the heap is destroyed.
+        let flag = DropFlag(leaked_dropped.clone());
+        ctx.register_closure("leaked", move |_ctx, _args| {
+            let _ = &flag;
+            Ok(Value::Null)
+        }, Some(0));
+        ctx.eval("Duktape.fin(leaked, function() {}); leaked = null;")
+            .unwrap();
+        ctx.gc();
+        assert!(!leaked_dropped.get());
+    }
+    assert!(leaked_dropped.get());
+}
+
+#[test]
+fn test_panicking_callbacks() {
+    let mut ctx = context::Context::new().unwrap();
+    ctx.set_catch_panics(true);
+    ctx.register("explode", test::rust_panic, Some(0));
+
+    // The script sees an InternalError, but the panic is still reported
+    // to Rust, even though the script caught it.
+    let err = ctx.eval("var caught; try { explode(); } \
+                        catch (e) { caught = e.name; }").unwrap_err();
+    assert!(err.is_panic());
+    assert_eq!(ErrorCode::Internal, err.code());
+    assert!(err.message().unwrap().contains("boom"));
+    assert_eq!(Value::String(Cow::Borrowed("InternalError")),
+               ctx.eval("caught").unwrap());
+
+    // The context is still usable afterwards.
+    assert_eq!(Value::Number(2.0), ctx.eval("1 + 1").unwrap());
 }
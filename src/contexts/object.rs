@@ -0,0 +1,323 @@
+use duktape_sys::*;
+
+use errors::base::*;
+use types::Value;
+use contexts::context::Context;
+use contexts::persistent::PersistentRef;
+use io::encoder::{Encoder, DuktapeEncodable};
+use io::decoder::DuktapeDecodable;
+
+/// A reference to a JavaScript object, which can be used to read and
+/// modify its properties.  Getters, setters and proxies may run JavaScript
+/// code, so every operation takes the `Context` to run it on, and errors
+/// they throw are returned as a `DuktapeError`.
+pub struct ObjectRef {
+    obj: PersistentRef
+}
+
+impl ObjectRef {
+    /// Wrap `r`, which must refer to an object (including arrays and
+    /// functions) on the heap of `ctx`.
+    pub fn from_ref(ctx: &mut Context, r: PersistentRef) ->
+        DuktapeResult<ObjectRef>
+    {
+        try!(ctx.check_ref(&r));
+        let is_object = unsafe {
+            ctx.push_ref(&r);
+            let ptr = ctx.as_mut_ptr();
+            let is_object = duk_is_object(ptr, -1) != 0;
+            duk_pop(ptr);
+            is_object
+        };
+        if is_object {
+            Ok(ObjectRef{obj: r})
+        } else {
+            Err(DuktapeError::new(ErrorCode::Type, "Value is not an object"))
+        }
+    }
+
+    /// The underlying persistent reference, which can be passed to
+    /// `Context::call_ref` or `Context::call_method`.
+    pub fn as_persistent(&self) -> &PersistentRef { &self.obj }
+
+    /// Push our object followed by `key`.
+    unsafe fn push_key(&self, ctx: &mut Context, key: &str) {
+        ctx.push_ref(&self.obj);
+        duk_push_lstring(ctx.as_mut_ptr(), key.as_ptr() as *const i8,
+                         key.len() as duk_size_t);
+    }
+
+    /// Get the property `key`, and decode it as a `T`.
+    pub fn get<T: DuktapeDecodable>(&self, ctx: &mut Context, key: &str) ->
+        DuktapeResult<T>
+    {
+        try!(ctx.check_ref(&self.obj));
+        unsafe {
+            ctx.run(|ctx| {
+                self.push_key(ctx, key);
+                let status =
+                    duk_rust_pprop(ctx.as_mut_ptr(), DUK_RUST_PROP_GET, 2);
+                ctx.pop_decoded(status)
+            })
+        }
+    }
+
+    /// Get the property `key` as a `Value`.
+    pub fn get_value(&self, ctx: &mut Context, key: &str) ->
+        DuktapeResult<Value<'static>>
+    {
+        try!(ctx.check_ref(&self.obj));
+        unsafe {
+            ctx.run(|ctx| {
+                self.push_key(ctx, key);
+                let status =
+                    duk_rust_pprop(ctx.as_mut_ptr(), DUK_RUST_PROP_GET, 2);
+                ctx.pop_result(status)
+            })
+        }
+    }
+
+    /// Get the property `key`, which must be an object, as an `ObjectRef`.
+    pub fn get_object(&self, ctx: &mut Context, key: &str) ->
+        DuktapeResult<ObjectRef>
+    {
+        try!(ctx.check_ref(&self.obj));
+        let r = try!(unsafe {
+            ctx.run(|ctx| {
+                self.push_key(ctx, key);
+                let ptr = ctx.as_mut_ptr();
+                let status = duk_rust_pprop(ptr, DUK_RUST_PROP_GET, 2);
+                if status != DUK_EXEC_SUCCESS { return Err(ctx.pop_error()); }
+                let result = ctx.persist(-1);
+                duk_pop(ptr);
+                result
+            })
+        });
+        ObjectRef::from_ref(ctx, r)
+    }
+
+    /// Set the property `key` to `value`.
+    pub fn set(&self, ctx: &mut Context, key: &str,
+               value: &DuktapeEncodable) -> DuktapeResult<()>
+    {
+        try!(ctx.check_ref(&self.obj));
+        unsafe {
+            ctx.run(|ctx| {
+                self.push_key(ctx, key);
+                let ptr = ctx.as_mut_ptr();
+                value.duktape_encode(&mut Encoder::new(ptr)).unwrap();
+                let status = duk_rust_pprop(ptr, DUK_RUST_PROP_PUT, 3);
+                ctx.pop_result(status).map(|_| ())
+            })
+        }
+    }
+
+    /// Does the object or its prototype chain have the property `key`?
+    /// This is the JavaScript `in` operator.
+    pub fn has(&self, ctx: &mut Context, key: &str) -> DuktapeResult<bool> {
+        self.bool_op(ctx, key, DUK_RUST_PROP_HAS)
+    }
+
+    /// Delete the property `key`.  Returns false if the property can't be
+    /// deleted, as a non-strict `delete` would.  Errors thrown by a proxy's
+    /// `deleteProperty` trap are returned as errors.
+    pub fn delete(&self, ctx: &mut Context, key: &str) ->
+        DuktapeResult<bool>
+    {
+        self.bool_op(ctx, key, DUK_RUST_PROP_DEL)
+    }
+
+    /// Run a `duk_rust_pprop` operation which returns a boolean.
+    fn bool_op(&self, ctx: &mut Context, key: &str, op: duk_int_t) ->
+        DuktapeResult<bool>
+    {
+        try!(ctx.check_ref(&self.obj));
+        unsafe {
+            ctx.run(|ctx| {
+                self.push_key(ctx, key);
+                let status = duk_rust_pprop(ctx.as_mut_ptr(), op, 2);
+                match try!(ctx.pop_result(status)) {
+                    Value::Bool(b) => Ok(b),
+                    _ => Err(DuktapeError::from_str("Expected a boolean"))
+                }
+            })
+        }
+    }
+
+    /// Iterate over the names of the object's properties.  `flags` may
+    /// include `DUK_ENUM_OWN_PROPERTIES_ONLY` and
+    /// `DUK_ENUM_INCLUDE_NONENUMERABLE`; with no flags, this visits the
+    /// same properties as a `for...in` loop.
+    pub fn keys<'a>(&self, ctx: &'a mut Context, flags: duk_uint_t) ->
+        DuktapeResult<Keys<'a>>
+    {
+        self.enumerate(ctx, flags).map(|e| Keys{e: e})
+    }
+
+    /// Iterate over the names and values of the object's properties.  See
+    /// `keys` for the meaning of `flags`.
+    pub fn entries<'a>(&self, ctx: &'a mut Context, flags: duk_uint_t) ->
+        DuktapeResult<Entries<'a>>
+    {
+        self.enumerate(ctx, flags).map(|e| Entries{e: e})
+    }
+
+    /// Push our object and an enumerator for it.
+    fn enumerate<'a>(&self, ctx: &'a mut Context, flags: duk_uint_t) ->
+        DuktapeResult<Enumerator<'a>>
+    {
+        try!(ctx.check_ref(&self.obj));
+        // Enumerating a proxy runs its `enumerate` or `ownKeys` trap.
+        try!(unsafe {
+            ctx.run(|ctx| {
+                ctx.push_ref(&self.obj);
+                ctx.push_ref(&self.obj);
+                let ptr = ctx.as_mut_ptr();
+                duk_push_uint(ptr, flags);
+                let status = duk_rust_pprop(ptr, DUK_RUST_PROP_ENUM, 2);
+                if status != DUK_EXEC_SUCCESS {
+                    let err = ctx.pop_error();
+                    duk_pop(ptr);
+                    return Err(err);
+                }
+                Ok(())
+            })
+        });
+        Ok(Enumerator{ctx: ctx})
+    }
+}
+
+/// An object and its enumerator, which we keep on top of the stack while
+/// iterating.  Both are popped when we're dropped.
+struct Enumerator<'a> {
+    /// We hold a mutable borrow so nobody else can use the stack.
+    ctx: &'a mut Context
+}
+
+impl<'a> Enumerator<'a> {
+    /// Push the next key, if there is one.
+    fn next_key(&mut self) -> bool {
+        unsafe { duk_next(self.ctx.as_mut_ptr(), -1, 0) != 0 }
+    }
+
+    /// Pop the key on top of the stack.
+    fn pop_key(&mut self) -> DuktapeResult<String> {
+        match try!(unsafe { self.ctx.pop_result(DUK_EXEC_SUCCESS) }) {
+            Value::String(key) => Ok(key.into_owned()),
+            _ => Err(DuktapeError::from_str("Expected a string key"))
+        }
+    }
+}
+
+impl<'a> Drop for Enumerator<'a> {
+    fn drop(&mut self) {
+        unsafe { duk_pop_2(self.ctx.as_mut_ptr()); }
+    }
+}
+
+/// An iterator over property names.  See `ObjectRef::keys`.
+pub struct Keys<'a> {
+    e: Enumerator<'a>
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = DuktapeResult<String>;
+
+    fn next(&mut self) -> Option<DuktapeResult<String>> {
+        if self.e.next_key() { Some(self.e.pop_key()) } else { None }
+    }
+}
+
+/// An iterator over property names and values.  See `ObjectRef::entries`.
+pub struct Entries<'a> {
+    e: Enumerator<'a>
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = DuktapeResult<(String, Value<'static>)>;
+
+    fn next(&mut self) -> Option<DuktapeResult<(String, Value<'static>)>> {
+        if !self.e.next_key() { return None; }
+        let value = unsafe {
+            // [ obj enum key ] -> [ obj enum key value ]
+            let ptr = self.e.ctx.as_mut_ptr();
+            self.e.ctx.run(|ctx| {
+                duk_dup(ptr, -3);
+                duk_dup(ptr, -2);
+                let status = duk_rust_pprop(ptr, DUK_RUST_PROP_GET, 2);
+                ctx.pop_result(status)
+            })
+        };
+        let key = self.e.pop_key();
+        Some(key.and_then(|key| value.map(|value| (key, value))))
+    }
+}
+
+#[test]
+fn test_object_refs() {
+    use std::borrow::Cow;
+    use ErrorCode;
+
+    let mut ctx = Context::new().unwrap();
+    ctx.eval("var config = {name: 'demo', retries: 3, \
+                            nested: {on: true}}; \
+              Object.defineProperty(config, 'hidden', \
+                                    {value: 1, enumerable: false}); \
+              var child = Object.create(config); child.own = 'yes'; \
+              var touchy = {get boom() { throw new TypeError('boom'); }}; \
+              var guarded = new Proxy({}, {deleteProperty: function () { \
+                  throw new TypeError('guarded'); \
+              }});")
+        .unwrap();
+    let global = ctx.global_object().unwrap();
+    let config = global.get_object(&mut ctx, "config").unwrap();
+
+    assert_eq!("demo", config.get::<String>(&mut ctx, "name").unwrap());
+    assert_eq!(3, config.get::<u32>(&mut ctx, "retries").unwrap());
+    assert!(config.get::<u32>(&mut ctx, "name").is_err());
+    let nested = config.get_object(&mut ctx, "nested").unwrap();
+    assert_eq!(Value::Bool(true), nested.get_value(&mut ctx, "on").unwrap());
+    assert!(config.get_object(&mut ctx, "name").is_err());
+
+    config.set(&mut ctx, "retries", &5.0f64).unwrap();
+    assert_eq!(Value::Number(5.0), ctx.eval("config.retries").unwrap());
+    assert!(config.has(&mut ctx, "toString").unwrap());
+    assert!(config.delete(&mut ctx, "retries").unwrap());
+    assert!(!config.has(&mut ctx, "retries").unwrap());
+    assert!(!config.delete(&mut ctx, "hidden").unwrap());
+    let guarded = global.get_object(&mut ctx, "guarded").unwrap();
+    let err = guarded.delete(&mut ctx, "x").unwrap_err();
+    assert_eq!(Some("guarded"), err.message());
+
+    let keys: Vec<String> = config.keys(&mut ctx, 0).unwrap()
+        .map(|k| k.unwrap()).collect();
+    assert_eq!(vec!("name", "nested"), keys);
+    let keys: Vec<String> =
+        config.keys(&mut ctx, DUK_ENUM_OWN_PROPERTIES_ONLY |
+                              DUK_ENUM_INCLUDE_NONENUMERABLE).unwrap()
+        .map(|k| k.unwrap()).collect();
+    assert_eq!(vec!("name", "nested", "hidden"), keys);
+
+    let child = global.get_object(&mut ctx, "child").unwrap();
+    let all: Vec<String> = child.keys(&mut ctx, 0).unwrap()
+        .map(|k| k.unwrap()).collect();
+    assert_eq!(vec!("own", "name", "nested"), all);
+    let own: Vec<(String, Value<'static>)> =
+        child.entries(&mut ctx, DUK_ENUM_OWN_PROPERTIES_ONLY).unwrap()
+        .map(|e| e.unwrap()).collect();
+    assert_eq!(vec!(("own".to_string(), Value::String(Cow::Borrowed("yes")))),
+               own);
+
+    // Errors thrown by getters are returned, not propagated as longjmps.
+    let touchy = global.get_object(&mut ctx, "touchy").unwrap();
+    let err = touchy.get_value(&mut ctx, "boom").unwrap_err();
+    assert_eq!(ErrorCode::Type, err.code());
+    let entries: Vec<_> = touchy.entries(&mut ctx, 0).unwrap().collect();
+    assert_eq!(1, entries.len());
+    assert!(entries[0].is_err());
+
+    let obj = ctx.new_object().unwrap();
+    obj.set(&mut ctx, "x", &1.0f64).unwrap();
+    assert_eq!(Value::Object(vec!(("x".to_string(), Value::Number(1.0)))),
+               ctx.get_ref(obj.as_persistent()).unwrap());
+}
@@ -0,0 +1,110 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use duktape_sys::*;
+
+use contexts::heap::heap_state;
+
+/// A reference to a JavaScript value which keeps it alive after it has been
+/// popped from the value stack, for example a JavaScript function passed to
+/// a Rust callback which we want to call later.  The value is stored in the
+/// heap stash, which scripts can't reach, and is removed from it when the
+/// `PersistentRef` is dropped.
+///
+/// A `PersistentRef` may outlive its `Context`.  Once the heap has been
+/// destroyed, it no longer refers to anything, and dropping it is a no-op.
+pub struct PersistentRef {
+    /// The heap's original context, which stays valid as long as the heap.
+    ctx: *mut duk_context,
+    /// Our index in the heap stash.
+    key: duk_uarridx_t,
+    /// Cleared when the heap is destroyed.
+    alive: Rc<Cell<bool>>
+}
+
+impl PersistentRef {
+    /// Store the value at `idx` in the heap stash.  Returns `None` if the
+    /// heap wasn't created by this library.
+    pub unsafe fn new(ctx: *mut duk_context, idx: duk_idx_t) ->
+        Option<PersistentRef>
+    {
+        let state = match heap_state(ctx) {
+            Some(state) => state,
+            None => return None
+        };
+        let key = state.next_ref_key();
+        let idx = duk_normalize_index(ctx, idx);
+        duk_push_heap_stash(ctx);
+        duk_dup(ctx, idx);
+        duk_put_prop_index(ctx, -2, key);
+        duk_pop(ctx);
+        Some(PersistentRef{ctx: state.main_ctx, key: key,
+                           alive: state.alive.clone()})
+    }
+
+    /// Does the heap holding our value still exist?
+    pub fn is_alive(&self) -> bool { self.alive.get() }
+
+    /// Is our value stored on the same heap as `ctx`?
+    pub unsafe fn belongs_to(&self, ctx: *mut duk_context) -> bool {
+        match heap_state(ctx) {
+            Some(state) => {
+                &*state.alive as *const Cell<bool> ==
+                    &*self.alive as *const Cell<bool>
+            }
+            None => false
+        }
+    }
+
+    /// Push our value onto the stack of `ctx`, which must belong to the
+    /// same heap, and which must still be alive.
+    pub unsafe fn push(&self, ctx: *mut duk_context) {
+        duk_push_heap_stash(ctx);
+        duk_get_prop_index(ctx, -1, self.key);
+        duk_remove(ctx, -2);
+    }
+}
+
+impl Drop for PersistentRef {
+    fn drop(&mut self) {
+        if !self.alive.get() { return; }
+        unsafe {
+            duk_push_heap_stash(self.ctx);
+            duk_del_prop_index(self.ctx, -1, self.key);
+            duk_pop(self.ctx);
+        }
+    }
+}
+
+#[test]
+fn test_persistent_refs() {
+    use std::cell::RefCell;
+    use Context;
+    use Value;
+
+    let mut ctx = Context::new().unwrap();
+    let obj = ctx.eval_to_ref("({answer: 42})").unwrap();
+    ctx.gc();
+    assert_eq!(Value::Object(vec!(("answer".to_string(), Value::Number(42.0)))),
+               ctx.get_ref(&obj).unwrap());
+
+    // Hold on to a value passed to a callback.
+    let saved: Rc<RefCell<Option<PersistentRef>>> = Rc::new(RefCell::new(None));
+    let saved2 = saved.clone();
+    ctx.register_closure("save", move |ctx, _args| {
+        *saved2.borrow_mut() = Some(try!(ctx.persist(0)));
+        Ok(Value::Undefined)
+    }, Some(1));
+    ctx.eval("save([1, 2]);").unwrap();
+    ctx.gc();
+    assert_eq!(Value::Array(vec!(Value::Number(1.0), Value::Number(2.0))),
+               ctx.get_ref(saved.borrow().as_ref().unwrap()).unwrap());
+
+    // References can't be used with other heaps, and can outlive their
+    // own.
+    let mut other = Context::new().unwrap();
+    assert!(other.get_ref(&obj).is_err());
+    drop(ctx);
+    assert!(!obj.is_alive());
+    drop(obj);
+}
@@ -0,0 +1,19 @@
+use contexts::persistent::PersistentRef;
+
+/// JavaScript code which has been compiled once, and which can be run many
+/// times without parsing it again.  Created by `Context::compile` or
+/// `Context::compile_function`, and run with `Context::run_script` or
+/// `Context::call_script`.
+pub struct CompiledScript {
+    func: PersistentRef
+}
+
+impl CompiledScript {
+    /// Wrap a reference to a compiled function.
+    pub fn new(func: PersistentRef) -> CompiledScript {
+        CompiledScript{func: func}
+    }
+
+    /// The compiled function.  Calling it runs the script.
+    pub fn function(&self) -> &PersistentRef { &self.func }
+}
@@ -0,0 +1,161 @@
+use rustc_serialize::Decodable;
+
+use duktape_sys::*;
+
+use errors::base::*;
+use types::Value;
+use contexts::context::Context;
+use contexts::callback::RawCallback;
+use io::encoder::DuktapeEncodable;
+use io::decoder::{Decoder, DuktapeDecodable};
+
+/// A Rust function or closure whose arguments can be decoded from
+/// JavaScript values, and whose result can be encoded as one.  This is
+/// implemented for any `FnMut(A, B, ...) -> DuktapeResult<R>` with up to
+/// six `DuktapeDecodable` arguments and a `DuktapeEncodable` result.  See
+/// `Context::register_typed`.
+///
+/// `Args` is a tuple of the argument types.  It only exists so that we can
+/// implement this trait once for each number of arguments.
+pub trait TypedCallback<Args> {
+    /// Wrap this function in a callback which checks and decodes its
+    /// arguments, using `name` in error messages.
+    fn into_callback(self, name: String) -> RawCallback;
+}
+
+/// Make sure we were passed exactly `expected` arguments.
+pub fn check_arity(name: &str, expected: usize, actual: usize) ->
+    DuktapeResult<()>
+{
+    if expected == actual { return Ok(()); }
+    Err(DuktapeError::new(
+        ErrorCode::Type,
+        &format!("{} expects {} argument{}, got {}", name, expected,
+                 if expected == 1 { "" } else { "s" }, actual)))
+}
+
+/// Decode argument `idx` of the current call as a `T`.
+pub fn decode_arg<T: DuktapeDecodable>(ctx: &mut Context, name: &str,
+                                   idx: usize) -> DuktapeResult<T>
+{
+    unsafe {
+        // The arguments are still at the bottom of the stack, and the
+        // decoder pops whatever it reads.
+        let ptr = ctx.as_mut_ptr();
+        duk_dup(ptr, idx as duk_idx_t);
+        let mut decoder = Decoder::new(ptr);
+        Decodable::decode(&mut decoder).map_err(|err: DuktapeError| {
+            let msg = err.message().unwrap_or("invalid value").to_string();
+            DuktapeError::new(ErrorCode::Type,
+                              &format!("{}: argument {}: {}", name, idx, msg))
+        })
+    }
+}
+
+/// Convert our result to a `Value` which can be returned to JavaScript.
+pub fn encode_result<R: DuktapeEncodable>(ctx: &mut Context, result: &R) ->
+    DuktapeResult<Value<'static>>
+{
+    unsafe {
+        ctx.push(result);
+        ctx.pop_result(DUK_EXEC_SUCCESS)
+    }
+}
+
+macro_rules! typed_callback {
+    ($count:expr; $($arg:ident $var:ident $idx:expr),*) => {
+        impl<F, R $(, $arg)*> TypedCallback<($($arg,)*)> for F
+            where F: FnMut($($arg),*) -> DuktapeResult<R> + 'static,
+                  R: DuktapeEncodable
+                  $(, $arg: DuktapeDecodable)*
+        {
+            #[allow(unused_variables)]
+            fn into_callback(mut self, name: String) -> RawCallback {
+                Box::new(move |ctx: &mut Context, arg_count: usize| {
+                    try!(check_arity(&name, $count, arg_count));
+                    $(let $var: $arg = try!(decode_arg(ctx, &name, $idx));)*
+                    let result = try!(self($($var),*));
+                    encode_result(ctx, &result)
+                })
+            }
+        }
+    }
+}
+
+typed_callback!(0;);
+typed_callback!(1; A a 0);
+typed_callback!(2; A a 0, B b 1);
+typed_callback!(3; A a 0, B b 1, C c 2);
+typed_callback!(4; A a 0, B b 1, C c 2, D d 3);
+typed_callback!(5; A a 0, B b 1, C c 2, D d 3, E e 4);
+typed_callback!(6; A a 0, B b 1, C c 2, D d 3, E e 4, G g 5);
+
+#[test]
+fn test_typed_callbacks() {
+    #[derive(RustcDecodable)]
+    struct Point { x: f64, y: f64 }
+
+    let mut ctx = Context::new().unwrap();
+    ctx.register_typed("add", |x: f64, y: f64| -> DuktapeResult<f64> {
+        Ok(x + y)
+    });
+    ctx.register_typed("greet", |name: String, loud: bool|
+                       -> DuktapeResult<String> {
+        Ok(format!("Hello, {}{}", name, if loud { "!" } else { "." }))
+    });
+    ctx.register_typed("answer", || -> DuktapeResult<u32> { Ok(42) });
+    ctx.register_typed("norm", |p: Point| -> DuktapeResult<f64> {
+        Ok((p.x * p.x + p.y * p.y).sqrt())
+    });
+    ctx.register_typed("range", |n: u32| -> DuktapeResult<Vec<u32>> {
+        Ok((0..n).collect())
+    });
+    let mut calls = 0;
+    ctx.register_typed("count", move || -> DuktapeResult<u32> {
+        calls += 1;
+        Ok(calls)
+    });
+
+    assert_eq!(Value::Number(5.0), ctx.eval("add(2, 3)").unwrap());
+    assert_eq!("Hello, JS!", ctx.eval_as::<String>("greet('JS', true)")
+               .unwrap());
+    assert_eq!(42, ctx.eval_as::<u32>("answer()").unwrap());
+    assert_eq!(5.0, ctx.eval_as::<f64>("norm({x: 3, y: 4})").unwrap());
+    assert_eq!(vec!(0, 1, 2), ctx.eval_as::<Vec<u32>>("range(3)").unwrap());
+    ctx.eval("count(); count();").unwrap();
+    assert_eq!(3, ctx.eval_as::<u32>("count()").unwrap());
+
+    let err = ctx.eval("add(1)").unwrap_err();
+    assert_eq!(ErrorCode::Type, err.code());
+    assert_eq!(Some("add expects 2 arguments, got 1"), err.message());
+    let err = ctx.eval("add(1, 'two')").unwrap_err();
+    assert_eq!(ErrorCode::Type, err.code());
+    assert_eq!(Some("add: argument 1: Expected number"), err.message());
+    let err = ctx.eval("range(-1)").unwrap_err();
+    assert_eq!(Some("range: argument 0: Expected u32"), err.message());
+    let err = ctx.eval("norm({x: 3, y: 'four'})").unwrap_err();
+    assert_eq!(Some("norm: argument 0: Expected number at .y"),
+               err.message());
+    let err = ctx.eval("norm({get x() { throw new Error('no'); }, y: 2})")
+        .unwrap_err();
+    assert_eq!(Some("norm: argument 0: no"), err.message());
+    assert_eq!(Value::Bool(true),
+               ctx.eval("try { greet(1, 2); } catch (e) { \
+                             e instanceof TypeError }").unwrap());
+
+    // Arguments are only read by the decoder, so getters run once, and
+    // values which can't be converted to a `Value` may still be decoded.
+    #[derive(RustcDecodable)]
+    struct Length { length: f64 }
+    ctx.register_typed("length", |l: Length| -> DuktapeResult<f64> {
+        Ok(l.length)
+    });
+    assert_eq!(1.0, ctx.eval_as::<f64>("var reads = 0; \
+                                        norm({get x() { reads++; return 3; }, \
+                                              y: 4}); \
+                                        reads").unwrap());
+    assert_eq!(4294967295.0,
+               ctx.eval_as::<f64>("var huge = []; \
+                                   huge.length = 4294967295; \
+                                   length(huge)").unwrap());
+}
@@ -8,6 +8,11 @@ use self::core::clone::Clone;
 
 use duktape_sys::*;
 
+use libc::c_void;
+use std::borrow::Cow;
+
+use types::Value;
+
 /// These are the standard error codes, which make it easy to return
 /// pre-defined errors from duktape functions implemented in Rust.
 #[allow(missing_docs)]
@@ -42,28 +47,218 @@ pub struct DuktapeError {
     /// `ErrorCode::Error` if we have nothing better.
     code: ErrorCode,
 
-    /// Errors have some sort of internal structure, but the duktape
-    /// documentation always just converts them to strings.  So that's all
-    /// we'll store for now.
-    message: Option<String>
+    /// The error message.  For a thrown JavaScript `Error`, this is its
+    /// `message` property; for any other thrown value, it's the value
+    /// converted to a string.
+    message: Option<String>,
+
+    /// The `name` of a thrown JavaScript `Error`, such as `"TypeError"`.
+    name: Option<String>,
+
+    /// The `fileName` of a thrown JavaScript `Error`.
+    file_name: Option<String>,
+
+    /// The `lineNumber` of a thrown JavaScript `Error`.
+    line_number: Option<u32>,
+
+    /// The `stack` of a thrown JavaScript `Error`, if tracebacks are
+    /// enabled.
+    stack: Option<String>,
+
+    /// If JavaScript code threw something other than an `Error` (for
+    /// example, `throw 42`), the value that it threw.
+    value: Option<ThrownValue>,
+
+    /// Was this error caused by a panic in a Rust callback?
+    panicked: bool,
+
+    /// Was the script interrupted for running too long?
+    timed_out: bool
 }
 
 impl DuktapeError {
     /// Create an error specifying just the error code.
     pub fn from_code(code: ErrorCode) -> DuktapeError {
-        DuktapeError{code: code, message: None}
+        DuktapeError{code: code, message: None, name: None, file_name: None,
+                     line_number: None, stack: None, value: None,
+                     panicked: false, timed_out: false}
     }
 
     /// Create an error, specifying an error message.
     pub fn from_str(message: &str) -> DuktapeError {
-        DuktapeError{code: ErrorCode::Error, message: Some(message.to_string())}
+        DuktapeError::new(ErrorCode::Error, message)
+    }
+
+    /// Create an error specifying both an error code and a message.  When
+    /// returned from a callback, this is thrown as the matching JavaScript
+    /// error class, e.g. `ErrorCode::Type` becomes a `TypeError`.
+    pub fn new(code: ErrorCode, message: &str) -> DuktapeError {
+        let mut err = DuktapeError::from_code(code);
+        err.message = Some(message.to_string());
+        err
+    }
+
+    /// The error code.
+    pub fn code(&self) -> ErrorCode { self.code }
+
+    /// The error message, if any.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(|s| s.as_str())
+    }
+
+    /// The name of the JavaScript error class, such as `"SyntaxError"`.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|s| s.as_str())
+    }
+
+    /// The file in which a JavaScript error was thrown.
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_ref().map(|s| s.as_str())
     }
+
+    /// The line on which a JavaScript error was thrown.
+    pub fn line_number(&self) -> Option<u32> { self.line_number }
+
+    /// The JavaScript stack trace, if one is available.
+    pub fn stack(&self) -> Option<&str> {
+        self.stack.as_ref().map(|s| s.as_str())
+    }
+
+    /// The value thrown by JavaScript code, if it wasn't an `Error`.
+    pub fn value(&self) -> Option<Value<'static>> {
+        self.value.as_ref().map(|v| v.to_value())
+    }
+
+    /// Did a Rust callback panic while running the script?  See
+    /// `Context::set_catch_panics`.
+    pub fn is_panic(&self) -> bool { self.panicked }
+
+    /// Was the script interrupted because it exceeded its deadline or
+    /// instruction budget?  See `Context::set_deadline`.
+    pub fn is_timeout(&self) -> bool { self.timed_out }
 }
 
 /// Re-exported within the crate, but not outside.
 pub fn err_code(err: &DuktapeError) -> ErrorCode { err.code.clone() }
 pub fn err_message(err: &DuktapeError) -> &Option<String> { &err.message }
 
+/// Build an error from the properties of a JavaScript `Error` object.
+pub fn err_from_js_error(name: String, message: Option<String>,
+                         file_name: Option<String>, line_number: Option<u32>,
+                         stack: Option<String>) -> DuktapeError
+{
+    let mut err = DuktapeError::from_code(code_for_name(&name));
+    err.name = Some(name);
+    err.message = message;
+    err.file_name = file_name;
+    err.line_number = line_number;
+    err.stack = stack;
+    err
+}
+
+/// A thrown value, as stored in a `DuktapeError`.  This mirrors `Value`,
+/// but keeps pointers as plain addresses so that errors can be sent to
+/// other threads, and numbers as their bits so that errors can be `Eq`.
+#[derive(Debug, PartialEq, Eq)]
+enum ThrownValue {
+    Undefined,
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<ThrownValue>),
+    Object(Vec<(String, ThrownValue)>),
+    Buffer(Vec<u8>),
+    Pointer(usize)
+}
+
+impl ThrownValue {
+    fn from_value(value: Value<'static>) -> ThrownValue {
+        match value {
+            Value::Undefined => ThrownValue::Undefined,
+            Value::Null => ThrownValue::Null,
+            Value::Bool(b) => ThrownValue::Bool(b),
+            Value::Number(n) => ThrownValue::Number(n.to_bits()),
+            Value::String(s) => ThrownValue::String(s.into_owned()),
+            Value::Array(elems) =>
+                ThrownValue::Array(elems.into_iter()
+                                   .map(ThrownValue::from_value).collect()),
+            Value::Object(props) =>
+                ThrownValue::Object(props.into_iter().map(|(k, v)| {
+                    (k, ThrownValue::from_value(v))
+                }).collect()),
+            Value::Buffer(bytes) => ThrownValue::Buffer(bytes.into_owned()),
+            Value::Pointer(p) => ThrownValue::Pointer(p as usize)
+        }
+    }
+
+    fn to_value(&self) -> Value<'static> {
+        match self {
+            &ThrownValue::Undefined => Value::Undefined,
+            &ThrownValue::Null => Value::Null,
+            &ThrownValue::Bool(b) => Value::Bool(b),
+            &ThrownValue::Number(bits) => Value::Number(f64::from_bits(bits)),
+            &ThrownValue::String(ref s) =>
+                Value::String(Cow::Owned(s.clone())),
+            &ThrownValue::Array(ref elems) =>
+                Value::Array(elems.iter().map(|e| e.to_value()).collect()),
+            &ThrownValue::Object(ref props) =>
+                Value::Object(props.iter()
+                              .map(|&(ref k, ref v)| (k.clone(), v.to_value()))
+                              .collect()),
+            &ThrownValue::Buffer(ref bytes) =>
+                Value::Buffer(Cow::Owned(bytes.clone())),
+            &ThrownValue::Pointer(p) => Value::Pointer(p as *mut c_void)
+        }
+    }
+}
+
+/// Build an error from a thrown value which isn't a JavaScript `Error`.
+pub fn err_from_js_value(value: Value<'static>, message: Option<String>) ->
+    DuktapeError
+{
+    let mut err = DuktapeError::from_code(ErrorCode::Error);
+    err.message = message;
+    err.value = Some(ThrownValue::from_value(value));
+    err
+}
+
+/// Build an error reporting a panic caught in a Rust callback.
+pub fn err_from_panic(message: &str) -> DuktapeError {
+    let mut err = DuktapeError::new(ErrorCode::Internal,
+                                    &format!("panic in Rust callback: {}",
+                                             message));
+    err.panicked = true;
+    err
+}
+
+/// Build an error reporting that a script ran for too long.
+pub fn err_from_timeout() -> DuktapeError {
+    let mut err = DuktapeError::new(ErrorCode::Range, "execution timeout");
+    err.timed_out = true;
+    err
+}
+
+/// Build an error reporting that a script exceeded its memory limit.
+pub fn err_from_alloc(limit: usize) -> DuktapeError {
+    DuktapeError::new(ErrorCode::Alloc,
+                      &format!("memory limit of {} bytes exceeded", limit))
+}
+
+/// Map the name of a standard JavaScript error class to an `ErrorCode`.
+fn code_for_name(name: &str) -> ErrorCode {
+    match name {
+        "EvalError" => ErrorCode::Eval,
+        "RangeError" => ErrorCode::Range,
+        "ReferenceError" => ErrorCode::Reference,
+        "SyntaxError" => ErrorCode::Syntax,
+        "TypeError" => ErrorCode::Type,
+        "URIError" => ErrorCode::Uri,
+        "InternalError" => ErrorCode::Internal,
+        _ => ErrorCode::Error
+    }
+}
+
 impl Error for DuktapeError {
     fn description(&self) -> &str { "script error:" }
 
@@ -72,10 +267,14 @@ impl Error for DuktapeError {
 
 impl fmt::Display for DuktapeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match (&self.message, self.code) {
-            (&Some(ref msg), _) => write!(f, "{}", msg),
-            (&None, ErrorCode::Error) => write!(f, "an unknown error occurred"),
-            (&None, code) => 
+        match (&self.name, &self.message, self.code) {
+            (&Some(ref name), &Some(ref msg), _) =>
+                write!(f, "{}: {}", name, msg),
+            (&Some(ref name), &None, _) => write!(f, "{}", name),
+            (&None, &Some(ref msg), _) => write!(f, "{}", msg),
+            (&None, &None, ErrorCode::Error) =>
+                write!(f, "an unknown error occurred"),
+            (&None, &None, code) =>
                 write!(f, "type: {:?} code: {:?}", code, code as duk_int_t)
         }
     }
@@ -1,62 +1,203 @@
 use std::iter::Iterator;
+use std::ops::Deref;
 use rustc_serialize::Decodable;
 use std::ffi::*;
+use cesu8::to_cesu8;
 
 use errors::base::*;
-use contexts::context::Context;
+use contexts::context::{Context, MAX_ARRAY_LENGTH, MAX_NESTING_DEPTH};
 use contexts::from_lstring;
 use duktape_sys::*;
 
-/// Translates JavaScript values into Rust values.
-#[allow(dead_code)] // WIP
+/// Translates JavaScript values into Rust values.  Every `read_*` method
+/// consumes the value on the top of the stack, so decoding a value pops
+/// it, whether or not decoding succeeds.
 pub struct Decoder {
     /// An internal `Context` object, for convenience.  We own this,
     /// because if we use a reference to somebody else's, the lifetimes
     /// make it very hard to work with &Encodable references.
-    ctx: Context
+    ctx: Context,
+
+    /// The keys of the maps we're currently decoding, innermost last.
+    /// JavaScript objects don't have a fixed key order we can index into,
+    /// so we snapshot the keys when we start reading each map.
+    map_keys: Vec<Vec<String>>,
+
+    /// Are we currently decoding a map key?  JavaScript object keys are
+    /// always strings, so we need to parse numeric keys ourselves.
+    reading_key: bool,
+
+    /// If the enum variant we're decoding was given as a bare string,
+    /// its name.  Such variants have no fields for us to read.
+    bare_variant: Option<String>,
+
+    /// Where we are inside the value being decoded, as a list of
+    /// components like `.field` or `[3]`, used to build error messages.
+    path: Vec<String>
 }
 
 impl Decoder {
     /// Create a new decoder which pops values from `ctx`.  If you create
     /// one of these, you're responsible for making sure it gets used
     /// safely.
-    #[allow(dead_code)] // WIP
     pub unsafe fn new(ctx: *mut duk_context) -> Decoder {
-        Decoder{ctx: Context::from_borrowed_mut_ptr(ctx)}
+        Decoder{ctx: Context::from_borrowed_mut_ptr(ctx), map_keys: vec!(),
+                reading_key: false, bare_variant: None, path: vec!()}
+    }
+
+    /// Build an error reporting that we expected `what` at our current
+    /// location.
+    fn expected(&self, what: &str) -> DuktapeError {
+        if self.path.is_empty() {
+            DuktapeError::from_str(&format!("Expected {}", what))
+        } else {
+            DuktapeError::from_str(&format!("Expected {} at {}", what,
+                                            self.path.concat()))
+        }
+    }
+
+    /// Call `f` to decode the value on top of the stack, with `component`
+    /// appended to our current path.  Like `Context::get`, we refuse to
+    /// follow cyclic or very deep data structures.
+    fn nested<T,F>(&mut self, component: String, f: F) -> DuktapeResult<T>
+        where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
+    {
+        if self.path.len() >= MAX_NESTING_DEPTH {
+            unsafe { duk_pop(self.ctx.as_mut_ptr()); }
+            return Err(self.expected("less deeply nested value"));
+        }
+        self.path.push(component);
+        let result = f(self);
+        self.path.pop();
+        result
+    }
+
+    /// Make sure the value on top of the stack passes `test`.  If it
+    /// doesn't, pop it and return an error.
+    unsafe fn expect(&mut self,
+                     test: unsafe extern "C" fn(*mut duk_context, duk_idx_t)
+                                                -> duk_bool_t,
+                     what: &str) -> DuktapeResult<()>
+    {
+        if test(self.ctx.as_mut_ptr(), -1) != 0 {
+            Ok(())
+        } else {
+            duk_pop(self.ctx.as_mut_ptr());
+            Err(self.expected(what))
+        }
+    }
+
+    /// Push a string onto the stack.
+    unsafe fn push_str(&mut self, s: &str) {
+        let encoded = to_cesu8(s);
+        let buf = encoded.deref();
+        duk_push_lstring(self.ctx.as_mut_ptr(), buf.as_ptr() as *const i8,
+                         buf.len() as duk_size_t);
+    }
+
+    /// Replace the key on top of the stack with that property of the
+    /// object below it.  Getters and proxies may throw, so this is a
+    /// protected read.  On error, only the object is left on the stack.
+    unsafe fn get_prop(&mut self) -> DuktapeResult<()> {
+        let ptr = self.ctx.as_mut_ptr();
+        if duk_check_stack(ptr, 2) == 0 {
+            duk_pop(ptr);
+            return Err(DuktapeError::from_str(
+                "Not enough stack space to decode value"));
+        }
+        // [ obj key ] -> [ obj obj key ] -> [ obj value ]
+        duk_dup(ptr, -2);
+        duk_swap_top(ptr, -2);
+        let status = duk_rust_pprop(ptr, DUK_RUST_PROP_GET, 2);
+        if status == DUK_EXEC_SUCCESS {
+            Ok(())
+        } else {
+            Err(self.ctx.pop_error())
+        }
+    }
+
+    /// Collect the own enumerable keys of the object on top of the stack.
+    unsafe fn object_keys(&mut self) -> DuktapeResult<Vec<String>> {
+        let ptr = self.ctx.as_mut_ptr();
+        let mut keys = vec!();
+        // Enumerating a proxy runs its traps, which may throw.
+        duk_dup_top(ptr);
+        duk_push_uint(ptr, DUK_ENUM_OWN_PROPERTIES_ONLY);
+        let status = duk_rust_pprop(ptr, DUK_RUST_PROP_ENUM, 2);
+        if status != DUK_EXEC_SUCCESS { return Err(self.ctx.pop_error()); }
+        while duk_next(ptr, -1, 0) != 0 {
+            let mut len = 0;
+            let str = duk_safe_to_lstring(ptr, -1, &mut len);
+            let key = from_lstring(str, len);
+            duk_pop(ptr);
+            match key {
+                Ok(key) => keys.push(key),
+                Err(err) => { duk_pop(ptr); return Err(err); }
+            }
+        }
+        duk_pop(ptr); // Remove enumerator.
+        Ok(keys)
+    }
+
+    /// Look up the current key of the map we're decoding.
+    fn map_key(&self, idx: usize) -> DuktapeResult<String> {
+        match self.map_keys.last() {
+            Some(keys) if idx < keys.len() => Ok(keys[idx].clone()),
+            _ => Err(DuktapeError::from_str("Map index out of range"))
+        }
     }
 }
 
-/// A value which can be encoded and passed to JavaScript code.
+/// A value which can be decoded from JavaScript data.
 pub trait DuktapeDecodable: Decodable {}
 impl<T: Decodable> DuktapeDecodable for T {}
 
-#[allow(unused_variables)]
 impl ::rustc_serialize::Decoder for Decoder {
     type Error = DuktapeError;
 
-    fn read_nil(&mut self) -> DuktapeResult<()>
-    {
-        unimplemented!()
+    fn read_nil(&mut self) -> DuktapeResult<()> {
+        unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            let is_nil = duk_is_null_or_undefined(ptr, -1) != 0;
+            duk_pop(ptr);
+            if is_nil { Ok(()) } else { Err(self.expected("null")) }
+        }
     }
 
-    read_and_convert!(read_usize-> usize,read_f64 -> f64);
-    read_and_convert!(read_u64  -> u64,  read_f64 -> f64);
-    read_and_convert!(read_u32  -> u32,  read_f64 -> f64);
-    read_and_convert!(read_u16  -> u16,  read_f64 -> f64);
-    read_and_convert!(read_u8   -> u8,   read_f64 -> f64);
-    read_and_convert!(read_isize-> isize,read_f64 -> f64);
-    read_and_convert!(read_i64  -> i64,  read_f64 -> f64);
-    read_and_convert!(read_i32  -> i32,  read_f64 -> f64);
-    read_and_convert!(read_i16  -> i16,  read_f64 -> f64);
-    read_and_convert!(read_i8   -> i8,   read_f64 -> f64);
-
-    read_with!(read_bool -> bool, duk_is_boolean, |self, idx| {
+    read_integer!(read_usize -> usize);
+    read_integer!(read_u64   -> u64);
+    read_integer!(read_u32   -> u32);
+    read_integer!(read_u16   -> u16);
+    read_integer!(read_u8    -> u8);
+    read_integer!(read_isize -> isize);
+    read_integer!(read_i64   -> i64);
+    read_integer!(read_i32   -> i32);
+    read_integer!(read_i16   -> i16);
+    read_integer!(read_i8    -> i8);
+
+    read_with!(read_bool -> bool, duk_is_boolean, "boolean", |self, idx| {
         Ok(duk_get_boolean(self.ctx.as_mut_ptr(), idx) != 0)
     });
 
-    read_with!(read_f64 -> f64, duk_is_number, |self, idx| {
-        Ok(duk_get_number(self.ctx.as_mut_ptr(), idx))
-    });
+    fn read_f64(&mut self) -> DuktapeResult<f64> {
+        unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            let result = if duk_is_number(ptr, -1) != 0 {
+                Ok(duk_get_number(ptr, -1))
+            } else if self.reading_key && duk_is_string(ptr, -1) != 0 {
+                // Numeric map keys were turned into strings by the encoder.
+                let mut len = 0;
+                let str = duk_get_lstring(ptr, -1, &mut len);
+                from_lstring(str, len).and_then(|s| {
+                    s.parse::<f64>().map_err(|_| self.expected("number"))
+                })
+            } else {
+                Err(self.expected("number"))
+            };
+            duk_pop(ptr);
+            result
+        }
+    }
     read_and_convert!(read_f32 -> f32, read_f64 -> f64);
 
     fn read_char(&mut self) -> DuktapeResult<char> {
@@ -82,141 +223,259 @@ impl ::rustc_serialize::Decoder for Decoder {
         }
     }
 
-    read_with!(read_str -> String, duk_is_string, |self, idx| {
+    read_with!(read_str -> String, duk_is_string, "string", |self, idx| {
         let mut len = 0;
         let ptr = duk_get_lstring(self.ctx.as_mut_ptr(), idx, &mut len);
         from_lstring(ptr, len)
     });
 
     // Compound types:
-    fn read_enum<T,F>(&mut self, name: &str,
-                    f: F) -> DuktapeResult<T>
+    fn read_enum<T,F>(&mut self, _name: &str, f: F) -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        f(self)
     }
 
-    fn read_enum_variant<T,F>(&mut self,
-                            names: &[&str],
-                            f: F)
-                            -> DuktapeResult<T>
+    /// Variants without arguments are encoded as plain strings, and other
+    /// variants as `{"variant": name, "fields": [...]}`.
+    fn read_enum_variant<T,F>(&mut self, names: &[&str], mut f: F) ->
+        DuktapeResult<T>
         where F: FnMut(&mut Decoder, usize) -> DuktapeResult<T>
     {
-        unimplemented!()
+        fn variant_idx(names: &[&str], name: &str) -> DuktapeResult<usize> {
+            match names.iter().position(|n| *n == name) {
+                Some(idx) => Ok(idx),
+                None => Err(DuktapeError::from_str(
+                    &format!("Unknown enum variant \"{}\"", name)))
+            }
+        }
+
+        unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            if duk_is_string(ptr, -1) != 0 {
+                let name = try!(self.read_str());
+                let idx = try!(variant_idx(names, &name));
+                let outer = self.bare_variant.take();
+                self.bare_variant = Some(name);
+                let result = f(self, idx);
+                self.bare_variant = outer;
+                return result;
+            }
+            try!(self.expect(duk_is_object, "enum variant"));
+
+            duk_push_string(ptr, VARIANT_PROP.as_ptr());
+            if let Err(err) = self.get_prop() {
+                duk_pop(ptr);
+                return Err(err);
+            }
+            let idx = match self.read_str()
+                .and_then(|name| variant_idx(names, &name))
+            {
+                Ok(idx) => idx,
+                Err(err) => { duk_pop(ptr); return Err(err); }
+            };
+
+            // Leave our fields on the stack for read_enum_variant_arg.
+            duk_push_string(ptr, FIELDS_PROP.as_ptr());
+            if let Err(err) = self.get_prop() {
+                duk_pop(ptr);
+                return Err(err);
+            }
+            if duk_is_array(ptr, -1) == 0 {
+                duk_pop_2(ptr);
+                return Err(self.expected("array of enum fields"));
+            }
+            let outer = self.bare_variant.take();
+            let result = f(self, idx);
+            self.bare_variant = outer;
+            duk_pop_2(ptr);
+            result
+        }
     }
-    fn read_enum_variant_arg<T,F>(&mut self,
-                                a_idx: usize,
-                                f: F)
-                                -> DuktapeResult<T>
+
+    fn read_enum_variant_arg<T,F>(&mut self, a_idx: usize, f: F) ->
+        DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        if let Some(ref name) = self.bare_variant {
+            return Err(self.expected(
+                &format!("fields for enum variant \"{}\"", name)));
+        }
+        self.read_seq_elt(a_idx, f)
     }
 
-    fn read_enum_struct_variant<T,F>(&mut self,
-                                   names: &[&str],
-                                   f: F)
-                                   -> DuktapeResult<T>
+    fn read_enum_struct_variant<T,F>(&mut self, names: &[&str], f: F) ->
+        DuktapeResult<T>
         where F: FnMut(&mut Decoder, usize) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_enum_variant(names, f)
     }
-    fn read_enum_struct_variant_field<T,F>(&mut self,
-                                         f_name: &str,
-                                         f_idx: usize,
-                                         f: F)
-                                         -> DuktapeResult<T>
+
+    fn read_enum_struct_variant_field<T,F>(&mut self, _f_name: &str,
+                                           f_idx: usize, f: F) ->
+        DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_enum_variant_arg(f_idx, f)
     }
 
-    fn read_struct<T,F>(&mut self, s_name: &str, len: usize, f: F)
-                      -> DuktapeResult<T>
+    fn read_struct<T,F>(&mut self, _s_name: &str, _len: usize, f: F) ->
+        DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        unsafe {
+            try!(self.expect(duk_is_object, "object"));
+            let result = f(self);
+            duk_pop(self.ctx.as_mut_ptr());
+            result
+        }
     }
-    fn read_struct_field<T,F>(&mut self,
-                            f_name: &str,
-                            f_idx: usize,
-                            f: F)
-                            -> DuktapeResult<T>
+
+    fn read_struct_field<T,F>(&mut self, f_name: &str, _f_idx: usize, f: F) ->
+        DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        unsafe {
+            self.push_str(f_name);
+            try!(self.get_prop());
+        }
+        self.nested(format!(".{}", f_name), f)
     }
 
     fn read_tuple<T,F>(&mut self, len: usize, f: F) -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_seq(|d, actual_len| {
+            if actual_len == len {
+                f(d)
+            } else {
+                Err(d.expected(&format!("array of length {}", len)))
+            }
+        })
     }
+
     fn read_tuple_arg<T,F>(&mut self, a_idx: usize, f: F) -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_seq_elt(a_idx, f)
     }
 
-    fn read_tuple_struct<T,F>(&mut self,
-                            s_name: &str,
-                            len: usize,
-                            f: F)
-                            -> DuktapeResult<T>
+    fn read_tuple_struct<T,F>(&mut self, _s_name: &str, len: usize, f: F) ->
+        DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_tuple(len, f)
     }
-    fn read_tuple_struct_arg<T,F>(&mut self,
-                                a_idx: usize,
-                                f: F)
-                                -> DuktapeResult<T>
+
+    fn read_tuple_struct_arg<T,F>(&mut self, a_idx: usize, f: F) ->
+        DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_tuple_arg(a_idx, f)
     }
 
     // Specialized types:
-    fn read_option<T,F>(&mut self, f: F) -> DuktapeResult<T>
+    fn read_option<T,F>(&mut self, mut f: F) -> DuktapeResult<T>
         where F: FnMut(&mut Decoder, bool) -> DuktapeResult<T>
     {
-        unimplemented!()
+        unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            if duk_is_null_or_undefined(ptr, -1) != 0 {
+                duk_pop(ptr);
+                f(self, false)
+            } else {
+                f(self, true)
+            }
+        }
     }
 
     fn read_seq<T,F>(&mut self, f: F) -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder, usize) -> DuktapeResult<T>
     {
-        unimplemented!()
+        unsafe {
+            try!(self.expect(duk_is_array, "array"));
+            // Our caller will probably preallocate `len` elements, and
+            // scripts can set an array's length to anything.
+            let len = duk_get_length(self.ctx.as_mut_ptr(), -1) as usize;
+            if len > MAX_ARRAY_LENGTH {
+                duk_pop(self.ctx.as_mut_ptr());
+                return Err(self.expected(&format!(
+                    "array of at most {} elements", MAX_ARRAY_LENGTH)));
+            }
+            let result = f(self, len);
+            duk_pop(self.ctx.as_mut_ptr());
+            result
+        }
     }
+
     fn read_seq_elt<T,F>(&mut self, idx: usize, f: F) -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        unsafe {
+            duk_push_uint(self.ctx.as_mut_ptr(), idx as duk_uint_t);
+            try!(self.get_prop());
+        }
+        self.nested(format!("[{}]", idx), f)
     }
 
     fn read_map<T,F>(&mut self, f: F) -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder, usize) -> DuktapeResult<T>
     {
-        unimplemented!()
+        unsafe {
+            try!(self.expect(duk_is_object, "object"));
+            let keys = match self.object_keys() {
+                Ok(keys) => keys,
+                Err(err) => { duk_pop(self.ctx.as_mut_ptr()); return Err(err); }
+            };
+            let len = keys.len();
+            self.map_keys.push(keys);
+            let result = f(self, len);
+            self.map_keys.pop();
+            duk_pop(self.ctx.as_mut_ptr());
+            result
+        }
     }
+
     fn read_map_elt_key<T,F>(&mut self, idx: usize, f: F) -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        let key = try!(self.map_key(idx));
+        unsafe { self.push_str(&key); }
+        self.reading_key = true;
+        let result = self.nested(format!(".{}", key), f);
+        self.reading_key = false;
+        result
     }
+
     fn read_map_elt_val<T,F>(&mut self, idx: usize, f: F) -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        let key = try!(self.map_key(idx));
+        unsafe {
+            self.push_str(&key);
+            try!(self.get_prop());
+        }
+        self.nested(format!(".{}", key), f)
     }
 
     // Failure
-    fn error(&mut self, err: &str) -> DuktapeError
-    {
-        unimplemented!()
+    fn error(&mut self, err: &str) -> DuktapeError {
+        if self.path.is_empty() {
+            DuktapeError::from_str(err)
+        } else {
+            DuktapeError::from_str(&format!("{} at {}", err, self.path.concat()))
+        }
     }
 }
 
+/// The property names used to encode enum variants with arguments.  These
+/// must match `io::encoder::Encoder::emit_enum_variant`.
+const VARIANT_PROP: [i8; 8] =
+    ['v' as i8, 'a' as i8, 'r' as i8, 'i' as i8, 'a' as i8, 'n' as i8,
+     't' as i8, 0];
+const FIELDS_PROP: [i8; 7] =
+    ['f' as i8, 'i' as i8, 'e' as i8, 'l' as i8, 'd' as i8, 's' as i8, 0];
+
 #[test]
 fn test_decoder() {
     //use std::collections::HashMap;
@@ -235,9 +494,7 @@ fn test_decoder() {
         value.duktape_encode(&mut encoder).unwrap();
         let mut decoder = unsafe { Decoder::new(ctx.as_mut_ptr()) };
         let decoded: DuktapeResult<T> = Decodable::decode(&mut decoder);
-
-        println!("value: {:?} \ndecoded: {:?}", value, decoded.unwrap());
-        assert_eq!(value, decoded.unwrap());
+        assert_eq!(value, &decoded.unwrap());
     }
 
     macro_rules! assert_decode {
@@ -280,7 +537,7 @@ fn test_decoder() {
     assert_decode!(ExStruct{x: 1.0, y: 2.0});
 
     //// Tuples.
-    assert_decode!(("hello", "rabbit"));
+    assert_decode!(("hello".to_string(), "rabbit".to_string()));
 
     //// Tuple structs.
     #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
@@ -297,10 +554,110 @@ fn test_decoder() {
     assert_decode!(seq);
 
     // Maps.
-    let mut hash: HashMap<String,int> = HashMap::new();
+    let mut hash: HashMap<String,i32> = HashMap::new();
     hash.insert("test".to_string(), 3);
-    assert_decode!(&hash);
-    let mut hash2: HashMap<int,int> = HashMap::new();
+    assert_decode!(hash);
+    let mut hash2: HashMap<i32,i32> = HashMap::new();
     hash2.insert(7, 3);
     assert_decode!(hash2);
 }
+
+#[test]
+fn test_decoder_errors() {
+    use std::collections::HashMap;
+    use io::encoder::Encoder;
+    use io::encoder::DuktapeEncodable;
+
+    let mut ctx = Context::new().unwrap();
+
+    fn decode_mismatch<T, U>(ctx: &mut Context, value: &T) -> bool
+        where T: DuktapeEncodable, U: DuktapeDecodable
+    {
+        unsafe {
+            let top = duk_get_top(ctx.as_mut_ptr());
+            let mut encoder = Encoder::new(ctx.as_mut_ptr());
+            value.duktape_encode(&mut encoder).unwrap();
+            let mut decoder = Decoder::new(ctx.as_mut_ptr());
+            let decoded: DuktapeResult<U> = Decodable::decode(&mut decoder);
+            // Failed decodes must still leave the stack balanced.
+            assert_eq!(top, duk_get_top(ctx.as_mut_ptr()));
+            decoded.is_err()
+        }
+    }
+
+    #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+    struct ExStruct { x: f64, y: f64 }
+    #[derive(RustcEncodable, RustcDecodable, PartialEq, Debug)]
+    struct ExOther { x: String }
+
+    assert!(decode_mismatch::<_, f64>(&mut ctx, &"string"));
+    assert!(decode_mismatch::<_, bool>(&mut ctx, &1.0f64));
+    assert!(decode_mismatch::<_, Vec<f64>>(&mut ctx, &ExStruct{x: 1.0, y: 2.0}));
+    assert!(decode_mismatch::<_, ExOther>(&mut ctx, &ExStruct{x: 1.0, y: 2.0}));
+    assert!(decode_mismatch::<_, (f64, f64)>(&mut ctx, &vec!(1.0f64)));
+
+    // Numbers must be integers which fit the type we're decoding.
+    assert_eq!(255, ctx.eval_as::<u8>("255").unwrap());
+    assert!(ctx.eval_as::<u8>("256").is_err());
+    assert!(ctx.eval_as::<u32>("-1").is_err());
+    assert!(ctx.eval_as::<i32>("1.5").is_err());
+    assert!(ctx.eval_as::<u64>("Math.pow(2, 64)").is_err());
+    assert_eq!(Some("Expected u32"),
+               ctx.eval_as::<u32>("NaN").unwrap_err().message());
+
+    // Only variants without fields may be written as bare strings.
+    #[derive(RustcDecodable, PartialEq, Debug)]
+    enum ExEnum { Foo, Bar(f64) }
+    assert!(!decode_mismatch::<_, ExEnum>(&mut ctx, &"Foo"));
+    assert!(decode_mismatch::<_, ExEnum>(&mut ctx, &"Bar"));
+    assert!(decode_mismatch::<_, Vec<ExEnum>>(&mut ctx, &vec!("Foo", "Bar")));
+
+    // Huge and cyclic values are refused, instead of exhausting our memory
+    // or our stack.
+    fn global_mismatch<U: DuktapeDecodable>(ctx: &mut Context, name: &str) ->
+        bool
+    {
+        unsafe {
+            let top = duk_get_top(ctx.as_mut_ptr());
+            let name = CString::new(name).unwrap();
+            duk_get_global_string(ctx.as_mut_ptr(), name.as_ptr());
+            let mut decoder = Decoder::new(ctx.as_mut_ptr());
+            let decoded: DuktapeResult<U> = Decodable::decode(&mut decoder);
+            assert_eq!(top, duk_get_top(ctx.as_mut_ptr()));
+            decoded.is_err()
+        }
+    }
+
+    #[derive(RustcDecodable, Debug)]
+    struct ExNode { next: Option<Box<ExNode>> }
+    ctx.eval("var huge = []; huge.length = 4294967295; \
+              var cyclic = {}; cyclic.next = cyclic; null").unwrap();
+    assert!(global_mismatch::<Vec<u8>>(&mut ctx, "huge"));
+    assert!(global_mismatch::<ExNode>(&mut ctx, "cyclic"));
+
+    // Getters which throw are reported as errors.
+    ctx.eval("var touchy = {get x() { throw new RangeError('no'); }, y: 2}; \
+              var sparse = []; \
+              Object.defineProperty(sparse, 0, \
+                  {get: function () { throw 1; }});").unwrap();
+    let err = ctx.eval_as::<ExStruct>("touchy").unwrap_err();
+    assert_eq!(ErrorCode::Range, err.code());
+    assert!(ctx.eval_as::<Vec<f64>>("sparse").is_err());
+    assert!(ctx.eval_as::<HashMap<String, f64>>("touchy").is_err());
+    assert_eq!(2.0, ctx.eval_as::<f64>("touchy.y").unwrap());
+
+    // So are proxy traps, whether they run while we read fields, map
+    // entries or enum variants, or while we list a map's keys.
+    ctx.eval("var no = function () { throw new RangeError('trap'); }; \
+              var getter = new Proxy({x: 1, y: 2}, {get: no}); \
+              var lister = new Proxy({x: 1}, {enumerate: no, ownKeys: no}); \
+              null").unwrap();
+    let err = ctx.eval_as::<ExStruct>("getter").unwrap_err();
+    assert_eq!(ErrorCode::Range, err.code());
+    assert_eq!(Some("trap"), err.message());
+    assert!(ctx.eval_as::<HashMap<String, f64>>("getter").is_err());
+    assert!(ctx.eval_as::<HashMap<String, f64>>("lister").is_err());
+    let err = ctx.eval_as::<ExEnum>("new Proxy({variant: 'Bar', fields: [1]}, \
+                                                {get: no})").unwrap_err();
+    assert_eq!(ErrorCode::Range, err.code());
+}
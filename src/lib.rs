@@ -21,19 +21,33 @@
 //! assert_eq!(Ok(Value::Number(3.0)), add_example());
 //! ```
 //!
-//! We also have preliminary support for defining JavaScript functions
-//! using Rust, but it's still too ugly to show off.
+//! JavaScript functions can also be defined in Rust.  `#[js_function]`
+//! takes care of checking and decoding the arguments and encoding the
+//! result, and `js_call!` makes calling back into JavaScript a little
+//! shorter.  Register these functions with an argument count of `None`,
+//! so that they can report calls with the wrong number of arguments.
+//!
+//! ```
+//! #[macro_use] extern crate duktape;
+//! use duktape::{Context,Value,DuktapeResult,js_function};
+//!
+//! #[js_function]
+//! fn greet(name: String) -> DuktapeResult<String> {
+//!     Ok(format!("Hello, {}!", name))
+//! }
+//!
+//! fn main() {
+//!     let mut ctx = Context::new().unwrap();
+//!     ctx.register("greet", greet, None);
+//!     assert_eq!(Ok(Value::String("Hello, JS!".into())),
+//!                js_call!(ctx, "greet", "JS"));
+//!     assert!(ctx.eval("greet()").is_err());
+//! }
+//! ```
 //!
 //! [Duktape]: http://duktape.org/
 
-#![feature(std_misc)]
-#![feature(collections)]
-#![feature(core)]
-#![feature(libc)]
-#![feature(convert)]
-
 #![allow(missing_docs)]
-#![allow(unused_features)]
 #![allow(unused_attributes)]
 #![allow(unused_imports)]
 #![allow(dead_code)]
@@ -43,8 +57,8 @@ extern crate rustc_serialize;
 extern crate libc;
 extern crate cesu8;
 
-#[macro_use] extern crate abort_on_panic;
 extern crate duktape_sys;
+extern crate duktape_macros;
 
 // use errors::{ErrorCode, DuktapeError, DuktapeResult};
 // use types::Value;
@@ -54,10 +68,32 @@ use duktape_sys::*;
 #[macro_use]
 mod macros;
 
-pub use contexts::callback::Callback;
+pub use contexts::callback::{Callback, RawCallback, FatalHandler, CallInfo,
+                             Dispatcher};
 pub use contexts::context::Context;
+pub use contexts::builder::{ContextBuilder, Allocator};
+pub use contexts::buffer::BufferGuard;
+pub use contexts::persistent::PersistentRef;
+pub use contexts::script::CompiledScript;
+pub use contexts::bytecode::BytecodeCache;
+pub use contexts::object::{ObjectRef, Keys, Entries};
+pub use contexts::class::{JsClass, Method};
+pub use contexts::typed::TypedCallback;
+pub use contexts::heap::{INSTRUCTIONS_PER_CHECK, MemoryStats};
 pub use types::Value;
-pub use errors::base::DuktapeResult;
+pub use errors::base::{DuktapeResult, DuktapeError, ErrorCode};
+pub use duktape_sys::{DUK_ENUM_OWN_PROPERTIES_ONLY,
+                      DUK_ENUM_INCLUDE_NONENUMERABLE};
+pub use io::encoder::DuktapeEncodable;
+pub use io::decoder::DuktapeDecodable;
+pub use duktape_macros::{js_function, JsClass};
+
+/// Helpers used by the code generated by `#[js_function]`.  These are not
+/// part of the public API.
+#[doc(hidden)]
+pub mod macro_support {
+    pub use contexts::typed::{check_arity, decode_arg, encode_result};
+}
 
 mod contexts;
 mod io;
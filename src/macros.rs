@@ -15,6 +15,41 @@ macro_rules! assert_stack_height_unchanged {
     }
 }
 
+/// Run `$body`, aborting the process with `$msg` if it panics.  Use this
+/// wherever a panic would otherwise unwind into duktape's C code.
+macro_rules! abort_on_panic {
+    ($msg:expr, $body:block) => {
+        match ::std::panic::catch_unwind(
+            ::std::panic::AssertUnwindSafe(|| $body))
+        {
+            Ok(result) => result,
+            Err(_) => {
+                use std::io::Write;
+                let _ = writeln!(::std::io::stderr(), "{}", $msg);
+                ::std::process::abort()
+            }
+        }
+    }
+}
+
+/// Read a number and convert it to the integer type `$ty`, refusing any
+/// number which isn't an integer or doesn't fit.
+macro_rules! read_integer {
+    ($name: ident -> $ty: ident) => {
+        fn $name(&mut self) -> DuktapeResult<$ty> {
+            let v = try!(self.read_f64());
+            // `MAX as f64` may round up, so compare against the next
+            // integer instead.
+            if v.fract() == 0.0 && v >= ::std::$ty::MIN as f64 &&
+                v < ::std::$ty::MAX as f64 + 1.0
+            {
+                Ok(v as $ty)
+            } else {
+                Err(self.expected(stringify!($ty)))
+            }
+        }
+    }
+}
 
 macro_rules! read_and_convert {
     ($name: ident -> $ty: ident, $reader: ident -> $in_ty: ident) => {
@@ -25,7 +60,7 @@ macro_rules! read_and_convert {
 }
 
 macro_rules! read_with {
-    ($name: ident -> $ty:ident, $tester:ident,
+    ($name: ident -> $ty:ident, $tester:ident, $expected:expr,
      |$slf:ident, $idx:ident| $reader:block) => {
         fn $name(&mut $slf) -> DuktapeResult<$ty> {
             unsafe {
@@ -36,9 +71,30 @@ macro_rules! read_with {
                     result
                 } else {
                     duk_pop($slf.ctx.as_mut_ptr());
-                    Err(DuktapeError::from_str("Expected number"))
+                    Err($slf.expected($expected))
                 }
             }
         }
     }
 }
+
+/// Call the global JavaScript function `name`, passing each argument by
+/// reference as a `DuktapeEncodable`.  This is shorthand for
+/// `Context::call`.
+///
+/// ```
+/// #[macro_use] extern crate duktape;
+/// use duktape::{Context, Value};
+///
+/// fn main() {
+///     let mut ctx = Context::new().unwrap();
+///     ctx.eval("function add(x, y) { return x+y; }").unwrap();
+///     assert_eq!(Ok(Value::Number(3.0)), js_call!(ctx, "add", 2.0f64, 1.0f64));
+/// }
+/// ```
+#[macro_export]
+macro_rules! js_call {
+    ($ctx:expr, $name:expr $(, $arg:expr)* $(,)*) => {
+        $ctx.call($name, &[$(&$arg as &$crate::DuktapeEncodable),*])
+    }
+}
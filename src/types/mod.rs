@@ -1,4 +1,5 @@
-use libc::types::os::arch::c95::c_double;
+use libc::c_double;
+use libc::c_void;
 use std::borrow::Cow;
 
 /// A value that can be passed to and from JavaScript.  This does not
@@ -14,5 +15,15 @@ pub enum Value<'a> {
     /// A JavaScript numeric value.
     Number(c_double),
     /// A JavaScript string value.
-    String(Cow<'a, str>)
+    String(Cow<'a, str>),
+    /// A JavaScript array.
+    Array(Vec<Value<'a>>),
+    /// A JavaScript object, represented as a list of its own enumerable
+    /// properties in insertion order.
+    Object(Vec<(String, Value<'a>)>),
+    /// A duktape buffer.  Values read from JavaScript are copied out of
+    /// the heap; use `Context::get_buffer` to access a buffer in place.
+    Buffer(Cow<'a, [u8]>),
+    /// A raw pointer, which JavaScript code can store but not use.
+    Pointer(*mut c_void)
 }